@@ -0,0 +1,123 @@
+use crate::config::AccessLogFormat;
+use http::{StatusCode, Version};
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// What's logged about a forwarded request.
+///
+/// The route stands in for the path and the token's hash for the user, so no
+/// ids or tokens are logged.
+pub struct Entry<'a> {
+    pub duration: Duration,
+    pub method: &'a str,
+    pub peer: IpAddr,
+    pub route: &'static str,
+    /// Length of the response body, if it's known up front.
+    pub size: Option<u64>,
+    pub status: StatusCode,
+    pub token: Option<u64>,
+    pub user_agent: Option<&'a str>,
+    pub version: Version,
+}
+
+/// Logs a request to the `access_log` target.
+pub fn log(format: AccessLogFormat, entry: &Entry<'_>) {
+    let token = entry.token.map(|hash| format!("{:016x}", hash));
+
+    match format {
+        AccessLogFormat::Clf => {
+            // The combined format, with the duration in milliseconds on the end
+            // as many servers add it.
+            info!(
+                target: "access_log",
+                "{} - {} [{}] \"{} {} {:?}\" {} {} \"-\" \"{}\" {}",
+                entry.peer,
+                token.as_deref().unwrap_or("-"),
+                clf_time(SystemTime::now()),
+                entry.method,
+                entry.route.replace(' ', "_"),
+                entry.version,
+                entry.status.as_u16(),
+                entry.size.map_or_else(|| "-".to_owned(), |size| size.to_string()),
+                entry.user_agent.unwrap_or("-").replace('"', "\\\""),
+                entry.duration.as_millis(),
+            );
+        }
+        AccessLogFormat::KeyValue => {
+            info!(
+                target: "access_log",
+                peer = %entry.peer,
+                token = token.as_deref().unwrap_or("-"),
+                method = entry.method,
+                route = entry.route,
+                status = entry.status.as_u16(),
+                size = entry.size.unwrap_or_default(),
+                duration_ms = entry.duration.as_millis() as u64,
+                "Request"
+            );
+        }
+    }
+}
+
+/// Formats a time as the Common Log Format does, such as
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Converts days since the epoch to a civil date, from Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn formats_clf_times() {
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(clf_time(at(971_182_536)), "10/Oct/2000:12:55:36 +0000");
+        // A leap day, and the last second of a leap year.
+        assert_eq!(clf_time(at(951_782_400)), "29/Feb/2000:00:00:00 +0000");
+        assert_eq!(clf_time(at(1_735_689_599)), "31/Dec/2024:23:59:59 +0000");
+    }
+
+    #[test]
+    fn formats_times_before_the_epoch_as_the_epoch() {
+        let before = UNIX_EPOCH - Duration::from_secs(1);
+
+        assert_eq!(clf_time(before), "01/Jan/1970:00:00:00 +0000");
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    error::{ChunkingRequest, ReloadingLogFilter, RequestError},
+    recent::RecentRequests,
+    usage::TokenUsage,
+    State,
+};
+use http::{header::CONTENT_TYPE, HeaderValue, Method};
+use hyper::{Body, Request, Response};
+use serde_json::{json, Value};
+use snafu::ResultExt;
+use std::sync::atomic::Ordering;
+use tracing::info;
+
+/// Header holding the admin secret.
+const SECRET_HEADER: &str = "x-admin-secret";
+
+/// Handles a request to an `/admin/` endpoint.
+pub async fn handle(state: &State, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    authorize(state, &request)?;
+
+    // Some endpoints read the body, which would move it out of the borrowed
+    // request.
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+
+    match (&method, path.as_str()) {
+        (&Method::GET, "/admin/config") => Ok(json_response(&json!({
+            "config": state.config().redacted(),
+            "features": {
+                "expose-metrics": cfg!(feature = "expose-metrics"),
+            },
+            "version": env!("CARGO_PKG_VERSION"),
+        }))),
+        (&Method::GET, "/admin/ratelimits") => {
+            let bot_id = bot_id_param(state, &request)?;
+            let ratelimits = state
+                .ratelimits
+                .as_ref()
+                .map(|ratelimits| ratelimits.snapshot(bot_id));
+
+            Ok(json_response(&ratelimits.unwrap_or_default()))
+        }
+        (&Method::GET, "/admin/maintenance") => Ok(json_response(&json!({
+            "enabled": state.maintenance.load(Ordering::Relaxed),
+        }))),
+        (&Method::POST, "/admin/maintenance") => {
+            let enabled = match query_param(&request, "enabled") {
+                Some("1") | Some("true") => true,
+                Some("0") | Some("false") => false,
+                value => {
+                    return Err(RequestError::InvalidAdminParameter {
+                        name: "enabled",
+                        value: value.unwrap_or_default().to_owned(),
+                    })
+                }
+            };
+
+            state.maintenance.store(enabled, Ordering::Relaxed);
+            info!(
+                "Maintenance mode turned {} through the admin endpoint",
+                if enabled { "on" } else { "off" }
+            );
+
+            Ok(json_response(&json!({ "enabled": enabled })))
+        }
+        (&Method::GET, "/admin/recent-requests") => {
+            let recent = state.recent.as_ref().map(RecentRequests::to_json);
+
+            Ok(json_response(&json!({ "requests": recent })))
+        }
+        (&Method::GET, "/admin/clients") => {
+            let tokens = state.token_usage.as_ref().map(TokenUsage::to_json);
+
+            Ok(json_response(&json!({ "clients": tokens })))
+        }
+        (&Method::POST, "/admin/flush-clients") => {
+            let rebuilt = state.client.rebuild();
+
+            if rebuilt {
+                info!("Clients flushed through the admin endpoint");
+            }
+
+            Ok(json_response(&json!({ "rebuilt": rebuilt })))
+        }
+        (&Method::GET, "/admin/log-level") => {
+            let filter = state
+                .log_filter
+                .with_current(ToString::to_string)
+                .context(ReloadingLogFilter)?;
+
+            Ok(json_response(&json!({ "filter": filter })))
+        }
+        (&Method::POST, "/admin/log-level") => {
+            let body = hyper::body::to_bytes(request.into_body())
+                .await
+                .context(ChunkingRequest)?;
+
+            set_log_filter(state, &String::from_utf8_lossy(&body))
+        }
+        _ => Err(RequestError::UnknownAdminEndpoint { path: path.clone() }),
+    }
+}
+
+/// Replaces the log filter with the directives given, or with the one from
+/// `RUST_LOG` again if there are none.
+fn set_log_filter(state: &State, directives: &str) -> Result<Response<Body>, RequestError> {
+    let directives = Some(directives.trim()).filter(|directives| !directives.is_empty());
+    let filter = crate::log_filter(directives, &state.config()).map_err(|_| {
+        RequestError::InvalidAdminParameter {
+            name: "filter",
+            value: directives.unwrap_or_default().to_owned(),
+        }
+    })?;
+    let shown = filter.to_string();
+
+    state
+        .log_filter
+        .reload(filter)
+        .context(ReloadingLogFilter)?;
+    info!("Log filter changed to {} through the admin endpoint", shown);
+
+    Ok(json_response(&json!({ "filter": shown })))
+}
+
+fn authorize(state: &State, request: &Request<Body>) -> Result<(), RequestError> {
+    let config = state.config();
+    let secret = config.admin_secret.as_deref().unwrap_or_default();
+    let given = request
+        .headers()
+        .get(SECRET_HEADER)
+        .map_or(&[][..], HeaderValue::as_bytes);
+
+    if !secret.is_empty() && constant_time_eq(secret.as_bytes(), given) {
+        Ok(())
+    } else {
+        Err(RequestError::AdminUnauthorized)
+    }
+}
+
+/// The bot named by the `bot_id` query parameter, or else the global token's.
+fn bot_id_param(state: &State, request: &Request<Body>) -> Result<Option<u64>, RequestError> {
+    match query_param(request, "bot_id") {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| RequestError::InvalidAdminParameter {
+                name: "bot_id",
+                value: value.to_owned(),
+            }),
+        None => Ok(state.default_bot_id),
+    }
+}
+
+/// The value of a query parameter, which isn't percent-decoded.
+fn query_param<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+
+        if parts.next() == Some(name) {
+            Some(parts.next().unwrap_or_default())
+        } else {
+            None
+        }
+    })
+}
+
+/// Compares two byte strings in time that depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn json_response(value: &Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(value.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    response
+}
@@ -0,0 +1,244 @@
+use crate::{
+    error::{InvalidJson, ReadingResponse, RequestError},
+    Connection, State,
+};
+use http::{
+    header::{
+        HeaderName, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, EXPECT, TRANSFER_ENCODING,
+    },
+    request::Parts,
+    HeaderValue, Method, Uri,
+};
+use hyper::{body, Body, Request, Response};
+use serde_json::{json, Map, Value};
+use snafu::ResultExt;
+use std::{
+    panic,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{Instrument, Span};
+
+/// Most requests a single batch may hold.
+pub(crate) const MAX_REQUESTS: usize = 50;
+
+/// Handles a `POST /batch` request, forwarding each of the requests in its body
+/// and answering with their responses, in the same order.
+///
+/// Requests are sent one after another, so a batch can depend on the order of
+/// its requests and never has two waiting on the same ratelimit at once. A
+/// batch with a deadline instead sends them all at once, and answers when the
+/// deadline passes with the responses received by then.
+pub async fn handle(
+    state: &Arc<State>,
+    connection: &Arc<Connection>,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let received = Instant::now();
+    let (mut parts, body) = request.into_parts();
+    let deadline = crate::take_deadline(&mut parts.headers, state.config().max_request_deadline)?;
+    let bytes = crate::read_body(body, state.config().max_body_size).await?;
+    let entries = match serde_json::from_slice::<Value>(&bytes).context(InvalidJson)? {
+        Value::Array(entries) => entries,
+        _ => return Err(invalid("body isn't an array of requests".into())),
+    };
+
+    if entries.len() > MAX_REQUESTS {
+        return Err(invalid(format!("more than {} requests", MAX_REQUESTS)));
+    }
+
+    // Every request is checked before any is sent, so a batch is either
+    // rejected whole or forwarded.
+    let requests = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            request_from(&parts, entry)
+                .ok_or_else(|| invalid(format!("request {} is malformed", index)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let responses = match deadline {
+        Some(deadline) => forward_until(state, connection, requests, received, deadline).await,
+        None => {
+            let mut responses = Vec::with_capacity(requests.len());
+
+            for request in requests {
+                responses.push(forward(state, connection, request).await);
+            }
+
+            responses
+        }
+    };
+
+    let mut response = Response::new(Body::from(Value::Array(responses).to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    Ok(response)
+}
+
+/// Forwards every request at once, giving those without a response once
+/// `deadline` has passed since the batch was received a `504 Gateway Timeout`
+/// entry marked as `timed_out`.
+async fn forward_until(
+    state: &Arc<State>,
+    connection: &Arc<Connection>,
+    requests: Vec<Request<Body>>,
+    received: Instant,
+    deadline: Duration,
+) -> Vec<Value> {
+    let paths = requests
+        .iter()
+        .map(|request| request.uri().path().to_owned())
+        .collect::<Vec<_>>();
+    let tasks = requests
+        .into_iter()
+        .map(|request| {
+            let state = state.clone();
+            let connection = connection.clone();
+            let forwarding = async move { forward(&state, &connection, request).await };
+
+            tokio::spawn(forwarding.instrument(Span::current()))
+        })
+        .collect::<Vec<_>>();
+
+    let mut responses = Vec::with_capacity(tasks.len());
+
+    for (mut task, path) in tasks.into_iter().zip(paths) {
+        let entry = match tokio::time::timeout_at((received + deadline).into(), &mut task).await {
+            Ok(Ok(entry)) => entry,
+            Ok(Err(why)) => panic::resume_unwind(why.into_panic()),
+            Err(_) => {
+                // Like a client giving up, which drops the request wherever it
+                // got to.
+                task.abort();
+
+                let why = RequestError::DeadlineExceeded {
+                    deadline_ms: deadline.as_millis() as u64,
+                };
+                let mut entry = error_entry(state, &path, why).await;
+                entry["timed_out"] = json!(true);
+
+                entry
+            }
+        };
+
+        responses.push(entry);
+    }
+
+    responses
+}
+
+/// Forwards a request from a batch, returning its entry in the batch's response.
+async fn forward(state: &State, connection: &Connection, request: Request<Body>) -> Value {
+    let path = request.uri().path().to_owned();
+    let response = match crate::handle_request(state, connection, request).await {
+        Ok(response) => response_json(response).await,
+        Err(why) => Err(why),
+    };
+
+    match response {
+        Ok(entry) => entry,
+        Err(why) => error_entry(state, &path, why).await,
+    }
+}
+
+/// The entry for a request from a batch that failed, in the configured error
+/// format.
+async fn error_entry(state: &State, path: &str, why: RequestError) -> Value {
+    let response = crate::error_response(&state.config(), path, why);
+
+    // The body of an error response is already in memory.
+    response_json(response).await.unwrap_or_default()
+}
+
+fn invalid(reason: String) -> RequestError {
+    RequestError::InvalidBatch { reason }
+}
+
+/// Builds a request from its entry in a batch, an object with its `method`,
+/// `path` and optionally `headers` and `body`.
+///
+/// The batch's own headers, such as `Authorization`, are sent with every
+/// request that doesn't set them itself, apart from those describing the
+/// batch's body. A body that isn't a string is sent as JSON.
+fn request_from(batch: &Parts, entry: &Value) -> Option<Request<Body>> {
+    let method = entry.get("method")?.as_str()?.to_ascii_uppercase();
+    let method = Method::from_bytes(method.as_bytes()).ok()?;
+    let uri = entry.get("path")?.as_str()?.parse::<Uri>().ok()?;
+
+    // Requests can only go where the proxy forwards to, not to other hosts.
+    if uri.authority().is_some() || !uri.path().starts_with('/') {
+        return None;
+    }
+
+    let mut headers = batch.headers.clone();
+
+    // Headers about the batch's own body don't apply to its requests, and the
+    // responses are embedded in the batch's as they are, so mustn't be
+    // compressed.
+    for name in &[
+        ACCEPT_ENCODING,
+        CONTENT_LENGTH,
+        CONTENT_TYPE,
+        EXPECT,
+        TRANSFER_ENCODING,
+    ] {
+        headers.remove(name);
+    }
+
+    if let Some(own) = entry.get("headers") {
+        for (name, value) in own.as_object()? {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value.as_str()?).ok()?,
+            );
+        }
+    }
+
+    let body = match entry.get("body") {
+        None | Some(Value::Null) => Body::empty(),
+        Some(Value::String(text)) => Body::from(text.clone()),
+        Some(value) => {
+            headers
+                .entry(CONTENT_TYPE)
+                .or_insert_with(|| HeaderValue::from_static("application/json"));
+
+            Body::from(value.to_string())
+        }
+    };
+
+    let mut request = Request::new(body);
+    *request.method_mut() = method;
+    *request.uri_mut() = uri;
+    *request.version_mut() = batch.version;
+    *request.headers_mut() = headers;
+
+    Some(request)
+}
+
+/// A response as an entry in the batch's response, with its `status`,
+/// `headers` and `body`. A JSON body is embedded as it is and any other as
+/// text.
+async fn response_json(response: Response<Body>) -> Result<Value, RequestError> {
+    let (parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body).await.context(ReadingResponse)?;
+
+    let headers = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str().to_owned(), json!(value.to_str().ok()?))))
+        .collect::<Map<_, _>>();
+    let body = Some(&bytes)
+        .filter(|_| crate::is_json(&parts.headers))
+        .and_then(|bytes| serde_json::from_slice(bytes).ok())
+        .unwrap_or_else(|| json!(String::from_utf8_lossy(&bytes)));
+
+    Ok(json!({
+        "status": parts.status.as_u16(),
+        "headers": headers,
+        "body": body,
+    }))
+}
@@ -0,0 +1,325 @@
+use crate::error::{ReadingResponse, RequestError};
+use http::{HeaderMap, StatusCode};
+use hyper::{
+    body::{self, Bytes},
+    Body, Response,
+};
+use snafu::ResultExt;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// A response read into memory so that it can be served more than once.
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    body: Bytes,
+    headers: HeaderMap,
+    status: StatusCode,
+}
+
+impl CachedResponse {
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub async fn read(response: Response<Body>) -> Result<Self, RequestError> {
+        let (parts, body) = response.into_parts();
+
+        Ok(Self {
+            body: body::to_bytes(body).await.context(ReadingResponse)?,
+            headers: parts.headers,
+            status: parts.status,
+        })
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+
+        response
+    }
+}
+
+/// A response and when it was fetched and stops being served.
+type Entry = (Instant, Instant, CachedResponse);
+
+type Slot = Arc<AsyncMutex<Option<Entry>>>;
+
+/// Successful responses kept for a while, keyed by whatever identifies them.
+///
+/// Only one fetch per key is ever in flight. Requests arriving while it is wait
+/// for it and are answered with its response whatever its status, even though
+/// only a successful one is kept afterwards. If the fetch fails without a
+/// response, the next request waiting fetches again.
+pub struct ResponseCache {
+    capacity: Option<usize>,
+    lifetime: Option<fn(&CachedResponse) -> Option<Duration>>,
+    slots: Mutex<HashMap<u64, Slot>>,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            capacity: None,
+            lifetime: None,
+            slots: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Serves responses for less than the TTL when `lifetime` says they go
+    /// stale sooner.
+    pub fn lifetime(mut self, lifetime: fn(&CachedResponse) -> Option<Duration>) -> Self {
+        self.lifetime = Some(lifetime);
+
+        self
+    }
+
+    /// Keeps no more than `capacity` keys, so that keys the client picks can't
+    /// fill memory.
+    ///
+    /// Once it's full, expired keys nothing is waiting on are dropped, and then
+    /// the one expiring soonest if none had. Keys with requests in flight are
+    /// never dropped, so they may go past it.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+
+        self
+    }
+
+    /// Returns the response for a key, calling `fetch` if there isn't a usable
+    /// one.
+    ///
+    /// With `revalidate`, a kept response isn't used unless it was fetched after
+    /// the call, so the response is fetched again and replaces it.
+    pub async fn get<F, Fut>(
+        &self,
+        key: u64,
+        revalidate: bool,
+        fetch: F,
+    ) -> Result<Response<Body>, RequestError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Response<Body>, RequestError>>,
+    {
+        let arrived = Instant::now();
+        let slot = {
+            let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+
+            if let Some(capacity) = self.capacity {
+                if slots.len() >= capacity && !slots.contains_key(&key) {
+                    make_room(&mut slots, capacity);
+                }
+            }
+
+            slots
+                .entry(key)
+                .or_insert_with(|| Arc::new(AsyncMutex::new(None)))
+                .clone()
+        };
+
+        let result = self.get_slot(&slot, arrived, revalidate, fetch).await;
+        self.prune(key, &slot);
+
+        result
+    }
+
+    async fn get_slot<F, Fut>(
+        &self,
+        slot: &Slot,
+        arrived: Instant,
+        revalidate: bool,
+        fetch: F,
+    ) -> Result<Response<Body>, RequestError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Response<Body>, RequestError>>,
+    {
+        let mut entry = slot.lock().await;
+
+        if let Some((fetched, expires, cached)) = &*entry {
+            if *fetched >= arrived || (!revalidate && Instant::now() < *expires) {
+                return Ok(cached.to_response());
+            }
+        }
+
+        let response = fetch().await?;
+
+        // Failures are kept too, but expire at once, so only the requests
+        // already waiting are answered with them.
+        let cached = CachedResponse::read(response).await?;
+        let response = cached.to_response();
+        let ttl = if !cached.status.is_success() {
+            Duration::from_secs(0)
+        } else {
+            match self.lifetime.and_then(|lifetime| lifetime(&cached)) {
+                Some(lifetime) => lifetime.min(self.ttl),
+                None => self.ttl,
+            }
+        };
+        let fetched = Instant::now();
+        *entry = Some((fetched, fetched + ttl, cached));
+
+        Ok(response)
+    }
+
+    /// Forgets a key's slot once nothing is waiting on it and its response has
+    /// expired, so that keys seen once don't stay around forever.
+    fn prune(&self, key: u64, slot: &Slot) {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // The map and the caller hold the only references, so nobody else can
+        // be waiting on the slot, and none can start to while the map is locked.
+        if Arc::strong_count(slot) > 2 {
+            return;
+        }
+
+        if expiry(slot).is_some_and(|expires| Instant::now() >= expires) {
+            slots.remove(&key);
+        }
+    }
+}
+
+/// When a slot's response expires, or `None` if something's using the slot.
+/// A slot without a response has already expired.
+fn expiry(slot: &Slot) -> Option<Instant> {
+    let entry = slot.try_lock().ok()?;
+
+    Some(entry.as_ref().map_or_else(Instant::now, |(_, expires, _)| *expires))
+}
+
+/// Drops expired slots nobody is using, and then the one expiring soonest if
+/// there are still `capacity` of them.
+fn make_room(slots: &mut HashMap<u64, Slot>, capacity: usize) {
+    let now = Instant::now();
+
+    // Only the map holds an unused slot, and nobody can start using it while
+    // the map is locked.
+    let unused = |slot: &Slot| Arc::strong_count(slot) == 1;
+    slots.retain(|_, slot| !unused(slot) || expiry(slot).is_none_or(|expires| expires > now));
+
+    if slots.len() < capacity {
+        return;
+    }
+
+    let soonest = slots
+        .iter()
+        .filter(|(_, slot)| unused(slot))
+        .filter_map(|(key, slot)| Some((expiry(slot)?, *key)))
+        .min();
+
+    if let Some((_, key)) = soonest {
+        slots.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn respond(status: StatusCode) -> Result<Response<Body>, RequestError> {
+        let mut response = Response::new(Body::from(status.as_str().to_owned()));
+        *response.status_mut() = status;
+
+        Ok(response)
+    }
+
+    /// Has three requests for the same key arrive while the first one's fetch
+    /// of a response with `status` is in flight, returning their statuses.
+    async fn fetch_at_once(cache: &ResponseCache, status: StatusCode) -> Vec<StatusCode> {
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            respond(status)
+        };
+
+        let (first, second, third) = tokio::join!(
+            cache.get(1, false, fetch),
+            cache.get(1, false, fetch),
+            cache.get(1, false, fetch),
+        );
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        vec![first, second, third]
+            .into_iter()
+            .map(|response| response.unwrap().status())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn shares_a_fetch_with_requests_waiting_on_it() {
+        let cache = ResponseCache::new(Duration::from_secs(0));
+
+        assert_eq!(
+            fetch_at_once(&cache, StatusCode::OK).await,
+            [StatusCode::OK; 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn shares_failures_with_requests_waiting_on_them() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let statuses = fetch_at_once(&cache, StatusCode::INTERNAL_SERVER_ERROR).await;
+
+        assert_eq!(statuses, [StatusCode::INTERNAL_SERVER_ERROR; 3]);
+
+        // The failure isn't kept for requests arriving afterwards.
+        let response = cache
+            .get(1, false, || async { respond(StatusCode::OK) })
+            .await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn keeps_successes_until_they_expire() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        cache
+            .get(1, false, || async { respond(StatusCode::OK) })
+            .await
+            .unwrap();
+
+        let kept = cache
+            .get(1, false, || async { respond(StatusCode::ACCEPTED) })
+            .await;
+        assert_eq!(kept.unwrap().status(), StatusCode::OK);
+
+        let revalidated = cache
+            .get(1, true, || async { respond(StatusCode::ACCEPTED) })
+            .await;
+        assert_eq!(revalidated.unwrap().status(), StatusCode::ACCEPTED);
+
+        let other_key = cache
+            .get(2, false, || async { respond(StatusCode::CREATED) })
+            .await;
+        assert_eq!(other_key.unwrap().status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn keeps_at_most_its_capacity_of_keys() {
+        let cache = ResponseCache::new(Duration::from_secs(60)).capacity(3);
+
+        for key in 0..100 {
+            cache
+                .get(key, false, || async { respond(StatusCode::OK) })
+                .await
+                .unwrap();
+
+            assert!(cache.slots.lock().unwrap().len() <= 3);
+        }
+
+        // The keys expiring soonest, which were fetched first, made room.
+        let slots = cache.slots.lock().unwrap();
+        let mut keys = slots.keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, [97, 98, 99]);
+    }
+}
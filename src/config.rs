@@ -0,0 +1,1095 @@
+use http::{
+    header::{HeaderName, AUTHORIZATION},
+    HeaderValue, Method, StatusCode, Uri,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+    error::Error,
+    ffi::OsString,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+/// Headers stripped from responses unless `STRIP_RESPONSE_HEADERS` says
+/// otherwise, which name the servers behind Discord or set cookies for them.
+const DEFAULT_STRIPPED_HEADERS: &[&str] = &[
+    "cf-cache-status",
+    "cf-ray",
+    "nel",
+    "report-to",
+    "server",
+    "set-cookie",
+    "via",
+];
+
+/// Variables read outside of [`Config`], which only take effect on a restart.
+pub const RESTART_VARIABLES: &[&str] = &[
+    "DISCORD_TOKEN",
+    "HOST",
+    "METRIC_KEY",
+    "PORT",
+    "TOKEN_SOURCE",
+];
+
+/// Settings read from the environment at startup, and again on a reload.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How each forwarded request is logged to the `access_log` target, if it
+    /// is.
+    pub access_log: Option<AccessLogFormat>,
+    /// Secret that requests to `/admin/` endpoints must present. The endpoints
+    /// are disabled without one.
+    pub admin_secret: Option<String>,
+    /// Whether `POST` requests may name their real method in `X-HTTP-Method-Override`.
+    pub allow_method_override: bool,
+    /// Whether requests without an `Authorization` header may pass their token
+    /// in the `token` query parameter.
+    pub allow_query_token: bool,
+    /// Methods that requests may be forwarded with, if not all that the proxy
+    /// supports.
+    pub allowed_methods: Option<Vec<Method>>,
+    /// How many tokens' bot ids are remembered.
+    pub auth_cache_size: Option<usize>,
+    /// How long an authorization hook's approval is reused for.
+    pub auth_hook_cache: Duration,
+    /// Header identifying the caller to the authorization hook and quotas.
+    pub auth_hook_key_header: HeaderName,
+    /// Longest the authorization hook may take to answer.
+    pub auth_hook_timeout: Duration,
+    /// Service that must approve each request before it's forwarded.
+    pub auth_hook_url: Option<Uri>,
+    /// Whether requests are counted per bot in the metrics.
+    pub bot_metrics: bool,
+    /// Requests per second each caller, by API key or bot id, may make.
+    pub caller_quotas: HashMap<String, u32>,
+    /// Header marking a request to be sent through the canary upstream.
+    pub canary_header: HeaderName,
+    /// Host with any path prefix, and whether to use plain HTTP for it, that
+    /// marked requests are sent to instead of Discord.
+    pub canary_upstream: Option<(String, bool)>,
+    /// Host that `/cdn/` requests are forwarded to, if they're proxied at all.
+    pub cdn_host: Option<String>,
+    /// Age at which the clients for the global token are replaced.
+    pub client_max_age: Option<Duration>,
+    /// Requests per second callers without a quota of their own may make.
+    pub default_caller_quota: Option<u32>,
+    /// `Content-Type` given to bodied requests that don't declare one.
+    pub default_content_type: Option<HeaderValue>,
+    /// Whether `POST /batch` forwards several requests at once.
+    pub enable_batch: bool,
+    /// Name of the deployment, such as `staging`, added to logs and metrics.
+    pub environment: Option<String>,
+    /// Shape of the bodies of the proxy's own error responses.
+    pub error_format: ErrorFormat,
+    /// Host with any path prefix, and whether to use plain HTTP for it, that
+    /// requests are sent to when Discord can't be reached.
+    pub fallback_upstream: Option<(String, bool)>,
+    /// How long `GET /gateway/bot` responses are served from cache.
+    pub gateway_bot_cache: Option<Duration>,
+    /// Requests per second that may be sent to Discord through the whole proxy.
+    pub global_max_rps: Option<u32>,
+    /// How many times, and for how long in total, the proxy waits out a `429`
+    /// from Discord and sends the request again itself, if it does.
+    pub handle_429: Option<(u32, Duration)>,
+    /// Most idempotency keys whose responses are kept at once.
+    pub idempotency_max_keys: usize,
+    /// How long a successful response is repeated to requests with the same
+    /// idempotency key.
+    pub idempotency_ttl: Option<Duration>,
+    /// How often every client sends a request to keep its connections warm.
+    pub keepalive_ping: Option<Duration>,
+    /// How long an inbound connection may go without a request before it's
+    /// closed.
+    pub inbound_idle_timeout: Option<Duration>,
+    /// What to do with injected headers that Discord's response already has.
+    pub injected_header_policy: HeaderPolicy,
+    /// Headers added to every response, from `INJECT_HEADER_<NAME>` variables.
+    pub injected_headers: Vec<(HeaderName, HeaderValue)>,
+    /// Directives the log filter is built from, from `RUST_LOG`.
+    pub log_filter: Option<String>,
+    /// Whether JSON request bodies are logged, pretty-printed and with secrets
+    /// redacted, at the debug level.
+    pub log_json_bodies: bool,
+    /// Whether the rate limit headers of Discord's responses are logged.
+    pub log_ratelimits: bool,
+    /// JSON body returned for every proxied request in maintenance mode.
+    pub maintenance_body: String,
+    /// Whether proxied requests are answered locally instead of sent to Discord.
+    pub maintenance_mode: bool,
+    /// Status returned for every proxied request in maintenance mode.
+    pub maintenance_status: StatusCode,
+    /// Most requests that may wait on a single ratelimit bucket at once.
+    pub max_bucket_queue: Option<usize>,
+    /// Longest request body accepted, in bytes.
+    pub max_body_size: Option<usize>,
+    /// Number of inbound connections that may be open at once.
+    pub max_connections: Option<usize>,
+    /// Longest deadline a client may give in `X-Request-Deadline-Ms`.
+    pub max_request_deadline: Duration,
+    /// Most distinct tokens a single connection may use.
+    pub max_tokens_per_connection: Option<usize>,
+    /// Longest path and query accepted, in bytes.
+    pub max_uri_length: Option<usize>,
+    /// File the port being listened on is written to.
+    pub port_file: Option<PathBuf>,
+    /// Whether connections start with a PROXY protocol header naming the client.
+    pub proxy_protocol: bool,
+    /// Longest a client may take to send a request body.
+    pub read_timeout: Option<Duration>,
+    /// How many of the latest forwarded requests are kept for the admin
+    /// endpoints, if any are.
+    pub record_requests: Option<usize>,
+    /// Whether bot tokens that don't name a bot id are rejected.
+    pub reject_invalid_bot_id: bool,
+    /// Whether JSON request bodies that don't parse are rejected.
+    pub reject_invalid_json: bool,
+    /// Whether requests for routes without a name in the metrics are rejected.
+    pub reject_unknown_routes: bool,
+    /// Whether responses get an `X-Proxy-RateLimit-Reset-After-Ms` header.
+    pub reset_after_ms_header: bool,
+    /// Smallest uncompressed response body that's gzipped for clients accepting
+    /// it, if any are.
+    pub response_gzip: Option<usize>,
+    /// Headers from Discord's responses passed on to clients, if not all of them.
+    pub response_header_allowlist: Option<Vec<HeaderName>>,
+    /// Fields removed from JSON responses, by the name of the route in the
+    /// metrics, each as the path of keys leading to it.
+    pub response_redactions: Redactions,
+    /// Upper bound of the random delay added to `Retry-After` on 429 responses.
+    pub retry_after_jitter_ms: u64,
+    /// Whether `SO_REUSEADDR` is set on the listening socket.
+    pub reuse_address: bool,
+    /// Whether `SO_REUSEPORT` is set on the listening socket, letting several
+    /// processes share the port.
+    pub reuse_port: bool,
+    /// Most requests for each route that may be in flight at once, by the name
+    /// of its twilight `Path` variant.
+    pub route_concurrency: HashMap<String, u32>,
+    /// Priority of each route for shedding, by the name of its twilight `Path`
+    /// variant.
+    pub route_priorities: HashMap<String, u32>,
+    /// `Server` header added to every response, naming the proxy's version and
+    /// instance.
+    pub server_header: Option<HeaderValue>,
+    /// Host with any path prefix, and whether to use plain HTTP for it, that
+    /// `GET` requests are copied to.
+    pub shadow_upstream: Option<(String, bool)>,
+    /// Requests in flight above which only the highest priority routes are
+    /// served.
+    pub shed_threshold: Option<usize>,
+    /// Whether identical concurrent `GET` requests share a single request to
+    /// Discord.
+    pub single_flight: bool,
+    /// Latency above which a proxied request is logged as slow.
+    pub slow_request: Option<Duration>,
+    /// How often a summary of the proxy's activity is logged.
+    pub stats_interval: Option<Duration>,
+    /// Whether bodied requests must carry a `Content-Type` Discord accepts.
+    pub strict_content_type: bool,
+    /// Headers removed from every response, for not exposing the infrastructure
+    /// behind Discord.
+    pub strip_response_headers: Vec<HeaderName>,
+    /// Idle time after which accepted connections are probed with TCP keepalives.
+    pub tcp_keepalive: Option<Duration>,
+    /// Whether `TCP_NODELAY` is set on accepted connections.
+    pub tcp_nodelay: bool,
+    /// Header that requests carry their token in instead of `Authorization`,
+    /// which is then left to another auth system and never sent to Discord.
+    pub token_header: Option<HeaderName>,
+    /// Header added to every request sent to Discord, for correlating requests
+    /// with Discord's logs during a support case.
+    pub tracking_header: Option<(HeaderName, HeaderValue)>,
+    /// Whether responses from Discord get an `X-Upstream-Duration-Ms` header.
+    pub upstream_duration_header: bool,
+}
+
+impl Config {
+    /// Reads the settings from the environment alone.
+    #[cfg(test)]
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Self::from_env_with(&HashMap::new())
+    }
+
+    /// Reads the settings from the environment, with the variables in `file`,
+    /// read with [`load_file`], taking precedence over it.
+    pub fn from_env_with(file: &HashMap<String, String>) -> Result<Self, Box<dyn Error>> {
+        let vars = Vars { file };
+
+        Ok(Self {
+            access_log: if vars.flag("ACCESS_LOG") {
+                Some(vars.var("ACCESS_LOG_FORMAT", AccessLogFormat::KeyValue)?)
+            } else {
+                None
+            },
+            admin_secret: vars.get("ADMIN_SECRET").ok(),
+            allow_method_override: vars.flag("ALLOW_METHOD_OVERRIDE"),
+            allow_query_token: vars.flag("ALLOW_QUERY_TOKEN"),
+            allowed_methods: vars.methods("ALLOWED_METHODS")?,
+            auth_cache_size: vars.optional("AUTH_CACHE_SIZE")?,
+            auth_hook_cache: Duration::from_secs(vars.var("AUTH_HOOK_CACHE_SECS", 10)?),
+            auth_hook_key_header: HeaderName::from_bytes(
+                vars.get("AUTH_HOOK_KEY_HEADER")
+                    .unwrap_or_else(|_| "X-Api-Key".into())
+                    .as_bytes(),
+            )?,
+            auth_hook_timeout: Duration::from_millis(vars.var("AUTH_HOOK_TIMEOUT_MS", 5000)?),
+            auth_hook_url: match vars.get("AUTH_HOOK_URL") {
+                Ok(url) => Some(url.parse()?),
+                Err(_) => None,
+            },
+            bot_metrics: vars.flag("BOT_METRICS"),
+            canary_header: HeaderName::from_bytes(
+                vars.get("CANARY_HEADER")
+                    .unwrap_or_else(|_| "X-Canary".into())
+                    .as_bytes(),
+            )?,
+            canary_upstream: vars.upstream_host("CANARY_UPSTREAM")?,
+            caller_quotas: vars.caller_quotas()?,
+            cdn_host: vars.get("CDN_HOST").ok(),
+            client_max_age: vars
+                .optional("CLIENT_MAX_AGE_SECS")?
+                .map(Duration::from_secs),
+            default_caller_quota: vars.optional("DEFAULT_CALLER_QUOTA")?,
+            default_content_type: match vars.get("DEFAULT_CONTENT_TYPE") {
+                Ok(value) => Some(HeaderValue::from_str(&value)?),
+                Err(_) => None,
+            },
+            enable_batch: vars.flag("ENABLE_BATCH"),
+            environment: vars.environment(),
+            error_format: vars.var("ERROR_FORMAT", ErrorFormat::Text)?,
+            fallback_upstream: vars.upstream_host("FALLBACK_UPSTREAM_URL")?,
+            gateway_bot_cache: vars
+                .optional("GATEWAY_BOT_CACHE_SECS")?
+                .map(Duration::from_secs),
+            global_max_rps: vars.optional("GLOBAL_MAX_RPS")?,
+            handle_429: if vars.flag("HANDLE_429") {
+                Some((
+                    vars.var("HANDLE_429_RETRIES", 1)?,
+                    Duration::from_millis(vars.var("HANDLE_429_MAX_WAIT_MS", 5000)?),
+                ))
+            } else {
+                None
+            },
+            idempotency_max_keys: vars.var("IDEMPOTENCY_MAX_KEYS", 10_000)?,
+            idempotency_ttl: vars
+                .optional("IDEMPOTENCY_TTL_SECS")?
+                .map(Duration::from_secs),
+            inbound_idle_timeout: vars
+                .optional("INBOUND_IDLE_TIMEOUT_SECS")?
+                .map(Duration::from_secs),
+            injected_header_policy: vars.var("INJECTED_HEADER_POLICY", HeaderPolicy::Override)?,
+            injected_headers: vars.injected_headers()?,
+            keepalive_ping: vars
+                .optional("KEEPALIVE_PING_SECS")?
+                .map(Duration::from_secs),
+            log_filter: vars.get("RUST_LOG").ok(),
+            log_json_bodies: vars.flag("LOG_JSON_BODIES"),
+            log_ratelimits: vars.flag("LOG_RATELIMITS"),
+            maintenance_body: vars.get("MAINTENANCE_BODY").unwrap_or_else(|_| {
+                r#"{"message": "The proxy is down for maintenance", "code": 0}"#.into()
+            }),
+            maintenance_mode: vars.flag("MAINTENANCE_MODE"),
+            maintenance_status: StatusCode::from_u16(vars.var("MAINTENANCE_STATUS", 503)?)?,
+            max_body_size: vars.optional("MAX_BODY_SIZE")?,
+            max_bucket_queue: vars.optional("MAX_BUCKET_QUEUE")?,
+            max_connections: vars.optional("MAX_CONNECTIONS")?,
+            max_request_deadline: Duration::from_millis(
+                vars.var("MAX_REQUEST_DEADLINE_MS", 60_000)?,
+            ),
+            max_tokens_per_connection: vars.optional("MAX_TOKENS_PER_CONNECTION")?,
+            max_uri_length: vars.optional("MAX_URI_LENGTH")?,
+            port_file: vars.get_os("PORT_FILE").map(PathBuf::from),
+            proxy_protocol: vars.flag("PROXY_PROTOCOL"),
+            read_timeout: vars.optional("READ_TIMEOUT_MS")?.map(Duration::from_millis),
+            record_requests: if vars.flag("RECORD_REQUESTS") {
+                Some(vars.var("RECORDED_REQUESTS", 100)?.max(1))
+            } else {
+                None
+            },
+            reject_invalid_bot_id: vars.flag("REJECT_INVALID_BOT_ID"),
+            reject_invalid_json: vars.flag("REJECT_INVALID_JSON"),
+            reject_unknown_routes: vars.flag("REJECT_UNKNOWN_ROUTES"),
+            reset_after_ms_header: vars.flag("RESET_AFTER_MS_HEADER"),
+            response_gzip: if vars.flag("RESPONSE_GZIP") {
+                Some(vars.var("RESPONSE_GZIP_MIN_BYTES", 1024)?)
+            } else {
+                None
+            },
+            response_header_allowlist: vars.header_names("RESPONSE_HEADER_ALLOWLIST")?,
+            response_redactions: vars.response_redactions()?,
+            retry_after_jitter_ms: vars.var("RETRY_AFTER_JITTER_MS", 0)?,
+            reuse_address: vars.flag_or("REUSE_ADDRESS", true),
+            reuse_port: vars.flag("REUSE_PORT"),
+            route_concurrency: vars.numbers("ROUTE_CONCURRENCY")?,
+            route_priorities: vars.numbers("ROUTE_PRIORITIES")?,
+            server_header: if vars.flag("SERVER_HEADER") {
+                Some(vars.server_header()?)
+            } else {
+                None
+            },
+            shadow_upstream: vars.upstream_host("SHADOW_UPSTREAM_URL")?,
+            shed_threshold: vars.optional("SHED_THRESHOLD")?,
+            single_flight: vars.flag("SINGLE_FLIGHT"),
+            slow_request: vars.optional("SLOW_REQUEST_MS")?.map(Duration::from_millis),
+            stats_interval: vars
+                .optional("STATS_INTERVAL_SECS")?
+                .map(Duration::from_secs),
+            strict_content_type: vars.flag("STRICT_CONTENT_TYPE"),
+            strip_response_headers: vars.stripped_headers()?,
+            tcp_keepalive: vars
+                .optional("TCP_KEEPALIVE_SECS")?
+                .map(Duration::from_secs),
+            tcp_nodelay: vars.flag("TCP_NODELAY"),
+            token_header: match vars.get("TOKEN_HEADER") {
+                Ok(name) => Some(HeaderName::from_bytes(name.as_bytes())?)
+                    .filter(|name| *name != AUTHORIZATION),
+                Err(_) => None,
+            },
+            tracking_header: match vars.get("TRACKING_HEADER_VALUE") {
+                Ok(value) => Some((
+                    HeaderName::from_bytes(
+                        vars.get("TRACKING_HEADER_NAME")
+                            .unwrap_or_else(|_| "X-Track".into())
+                            .as_bytes(),
+                    )?,
+                    HeaderValue::from_str(&value)?,
+                )),
+                Err(_) => None,
+            },
+            upstream_duration_header: vars.flag("UPSTREAM_DURATION_HEADER"),
+        })
+    }
+
+    /// The settings as JSON, without the admin secret or any header values.
+    pub fn redacted(&self) -> Value {
+        json!({
+            "access_log_format": self.access_log.map(AccessLogFormat::as_str),
+            "allow_method_override": self.allow_method_override,
+            "allow_query_token": self.allow_query_token,
+            "allowed_methods": self
+                .allowed_methods
+                .as_ref()
+                .map(|methods| methods.iter().map(Method::as_str).collect::<Vec<_>>()),
+            "auth_cache_size": self.auth_cache_size,
+            "auth_hook_cache_secs": self.auth_hook_cache.as_secs(),
+            "auth_hook_key_header": self.auth_hook_key_header.as_str(),
+            "auth_hook_timeout_ms": self.auth_hook_timeout.as_millis() as u64,
+            "auth_hook_url": self.auth_hook_url.as_ref().map(Uri::to_string),
+            "bot_metrics": self.bot_metrics,
+            "canary_header": self.canary_header.as_str(),
+            "caller_quotas": self.caller_quotas.len(),
+            "canary_upstream": self.canary_upstream.as_ref().map(|(host, _)| host),
+            "cdn_host": self.cdn_host,
+            "client_max_age_secs": self.client_max_age.map(|age| age.as_secs()),
+            "default_caller_quota": self.default_caller_quota,
+            "default_content_type": self
+                .default_content_type
+                .as_ref()
+                .and_then(|value| value.to_str().ok()),
+            "enable_batch": self.enable_batch,
+            "environment": self.environment,
+            "error_format": self.error_format.as_str(),
+            "fallback_upstream": self.fallback_upstream.as_ref().map(|(host, _)| host),
+            "gateway_bot_cache_secs": self.gateway_bot_cache.map(|ttl| ttl.as_secs()),
+            "global_max_rps": self.global_max_rps,
+            "handle_429_max_wait_ms": self
+                .handle_429
+                .map(|(_, max_wait)| max_wait.as_millis() as u64),
+            "handle_429_retries": self.handle_429.map(|(retries, _)| retries),
+            "idempotency_max_keys": self.idempotency_max_keys,
+            "idempotency_ttl_secs": self.idempotency_ttl.map(|ttl| ttl.as_secs()),
+            "inbound_idle_timeout_secs": self.inbound_idle_timeout.map(|timeout| timeout.as_secs()),
+            "injected_header_policy": self.injected_header_policy.as_str(),
+            "injected_headers": self
+                .injected_headers
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            "keepalive_ping_secs": self.keepalive_ping.map(|interval| interval.as_secs()),
+            "log_filter": self.log_filter,
+            "log_json_bodies": self.log_json_bodies,
+            "log_ratelimits": self.log_ratelimits,
+            "maintenance_mode": self.maintenance_mode,
+            "maintenance_status": self.maintenance_status.as_u16(),
+            "max_body_size": self.max_body_size,
+            "max_bucket_queue": self.max_bucket_queue,
+            "max_connections": self.max_connections,
+            "max_request_deadline_ms": self.max_request_deadline.as_millis() as u64,
+            "max_tokens_per_connection": self.max_tokens_per_connection,
+            "max_uri_length": self.max_uri_length,
+            "port_file": self.port_file,
+            "proxy_protocol": self.proxy_protocol,
+            "read_timeout_ms": self.read_timeout.map(|timeout| timeout.as_millis() as u64),
+            "record_requests": self.record_requests,
+            "reject_invalid_bot_id": self.reject_invalid_bot_id,
+            "reject_invalid_json": self.reject_invalid_json,
+            "reject_unknown_routes": self.reject_unknown_routes,
+            "reset_after_ms_header": self.reset_after_ms_header,
+            "response_gzip_min_bytes": self.response_gzip,
+            "response_header_allowlist": self
+                .response_header_allowlist
+                .as_ref()
+                .map(|names| names.iter().map(HeaderName::as_str).collect::<Vec<_>>()),
+            "response_redactions": self
+                .response_redactions
+                .iter()
+                .map(|(route, paths)| {
+                    let paths = paths.iter().map(|path| path.join(".")).collect::<Vec<_>>();
+
+                    (route.clone(), json!(paths))
+                })
+                .collect::<serde_json::Map<_, _>>(),
+            "retry_after_jitter_ms": self.retry_after_jitter_ms,
+            "reuse_address": self.reuse_address,
+            "reuse_port": self.reuse_port,
+            "route_concurrency": self.route_concurrency,
+            "route_priorities": self.route_priorities,
+            "server_header": self
+                .server_header
+                .as_ref()
+                .and_then(|value| value.to_str().ok()),
+            "shadow_upstream": self.shadow_upstream.as_ref().map(|(host, _)| host),
+            "shed_threshold": self.shed_threshold,
+            "single_flight": self.single_flight,
+            "slow_request_ms": self.slow_request.map(|slow| slow.as_millis() as u64),
+            "stats_interval_secs": self.stats_interval.map(|interval| interval.as_secs()),
+            "strict_content_type": self.strict_content_type,
+            "strip_response_headers": self
+                .strip_response_headers
+                .iter()
+                .map(HeaderName::as_str)
+                .collect::<Vec<_>>(),
+            "tcp_keepalive_secs": self.tcp_keepalive.map(|time| time.as_secs()),
+            "tcp_nodelay": self.tcp_nodelay,
+            "token_header": self.token_header.as_ref().map(HeaderName::as_str),
+            "tracking_header": self.tracking_header.as_ref().map(|(name, _)| name.as_str()),
+            "upstream_duration_header": self.upstream_duration_header,
+        })
+    }
+
+    /// These settings with those that the running proxy was built around, and
+    /// so can't change until it's restarted, kept as they are in `running`.
+    pub fn keep_startup_settings(self, running: &Config) -> Self {
+        let running = running.clone();
+
+        Self {
+            admin_secret: running.admin_secret,
+            auth_cache_size: running.auth_cache_size,
+            auth_hook_cache: running.auth_hook_cache,
+            auth_hook_timeout: running.auth_hook_timeout,
+            auth_hook_url: running.auth_hook_url,
+            bot_metrics: running.bot_metrics,
+            caller_quotas: running.caller_quotas,
+            canary_upstream: running.canary_upstream,
+            client_max_age: running.client_max_age,
+            default_caller_quota: running.default_caller_quota,
+            environment: running.environment,
+            fallback_upstream: running.fallback_upstream,
+            gateway_bot_cache: running.gateway_bot_cache,
+            global_max_rps: running.global_max_rps,
+            idempotency_max_keys: running.idempotency_max_keys,
+            idempotency_ttl: running.idempotency_ttl,
+            inbound_idle_timeout: running.inbound_idle_timeout,
+            keepalive_ping: running.keepalive_ping,
+            max_bucket_queue: running.max_bucket_queue,
+            max_connections: running.max_connections,
+            port_file: running.port_file,
+            proxy_protocol: running.proxy_protocol,
+            record_requests: running.record_requests,
+            reuse_address: running.reuse_address,
+            reuse_port: running.reuse_port,
+            route_concurrency: running.route_concurrency,
+            shadow_upstream: running.shadow_upstream,
+            single_flight: running.single_flight,
+            stats_interval: running.stats_interval,
+            tcp_keepalive: running.tcp_keepalive,
+            tcp_nodelay: running.tcp_nodelay,
+            ..self
+        }
+    }
+}
+
+/// How the access log renders a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessLogFormat {
+    /// A line in the combined log format, as Apache writes it.
+    Clf,
+    /// The request's details as structured fields.
+    KeyValue,
+}
+
+impl AccessLogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Clf => "clf",
+            Self::KeyValue => "kv",
+        }
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = UnknownAccessLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clf" => Ok(Self::Clf),
+            "kv" => Ok(Self::KeyValue),
+            _ => Err(UnknownAccessLogFormat(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownAccessLogFormat(String);
+
+impl fmt::Display for UnknownAccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "access log format {:?} isn't clf or kv", self.0)
+    }
+}
+
+impl Error for UnknownAccessLogFormat {}
+
+/// Shape of the bodies of the proxy's own error responses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorFormat {
+    /// An RFC 7807 `application/problem+json` document.
+    ProblemJson,
+    /// The error's message as plain text.
+    Text,
+}
+
+impl ErrorFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ProblemJson => "problem+json",
+            Self::Text => "text",
+        }
+    }
+}
+
+impl FromStr for ErrorFormat {
+    type Err = UnknownErrorFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "problem+json" => Ok(Self::ProblemJson),
+            "text" => Ok(Self::Text),
+            _ => Err(UnknownErrorFormat(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownErrorFormat(String);
+
+impl fmt::Display for UnknownErrorFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error format {:?} isn't problem+json or text", self.0)
+    }
+}
+
+impl Error for UnknownErrorFormat {}
+
+/// How an injected header is combined with one of the same name in a response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HeaderPolicy {
+    /// Both values are sent.
+    Append,
+    /// The response's value is kept.
+    Keep,
+    /// The injected value replaces the response's.
+    Override,
+}
+
+impl HeaderPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Append => "append",
+            Self::Keep => "keep",
+            Self::Override => "override",
+        }
+    }
+}
+
+impl FromStr for HeaderPolicy {
+    type Err = UnknownHeaderPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(Self::Append),
+            "keep" => Ok(Self::Keep),
+            "override" => Ok(Self::Override),
+            _ => Err(UnknownHeaderPolicy(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownHeaderPolicy(String);
+
+impl fmt::Display for UnknownHeaderPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "header policy {:?} isn't append, keep or override",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownHeaderPolicy {}
+
+/// Fields removed from JSON responses, by the name of the route in the metrics,
+/// each as the path of keys leading to it.
+pub type Redactions = HashMap<String, Vec<Vec<String>>>;
+
+/// Name of the deployment, from `ENVIRONMENT` or else `DEPLOYMENT`, as the
+/// config file read at startup or else the environment sets it.
+#[cfg(feature = "expose-metrics")]
+pub fn environment() -> Option<String> {
+    Vars {
+        file: startup_file(),
+    }
+    .environment()
+}
+
+/// Reads a file of `NAME=value` lines, where blank lines and those starting
+/// with `#` are skipped, for [`Config::from_env_with`]. The environment is left
+/// as it is.
+pub fn load_file(path: &Path) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let mut vars = HashMap::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or_default().trim();
+        let value = parts
+            .next()
+            .filter(|_| !name.is_empty())
+            .ok_or_else(|| format!("line {} of {} isn't NAME=value", number + 1, path.display()))?
+            .trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        vars.insert(name.to_owned(), value.to_owned());
+    }
+
+    Ok(vars)
+}
+
+/// Reads a variable from `file`, or else from the environment.
+pub fn lookup(file: &HashMap<String, String>, name: &str) -> Result<String, VarError> {
+    match file.get(name) {
+        Some(value) => Ok(value.clone()),
+        None => env::var(name),
+    }
+}
+
+/// Variables of the config file read at startup, set once by [`keep_startup_file`].
+static STARTUP_FILE: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Keeps the variables of the config file read at startup, for what's read
+/// before a [`Config`] can be passed to it, such as the metrics' names.
+pub fn keep_startup_file(file: HashMap<String, String>) {
+    let _ = STARTUP_FILE.set(file);
+}
+
+/// The variables [`keep_startup_file`] kept, if any were.
+pub fn startup_file() -> &'static HashMap<String, String> {
+    STARTUP_FILE.get_or_init(HashMap::new)
+}
+
+/// The environment, with the variables from a config file over it.
+#[derive(Clone, Copy)]
+struct Vars<'a> {
+    file: &'a HashMap<String, String>,
+}
+
+impl Vars<'_> {
+    /// Reads a variable, as [`env::var`] does.
+    fn get(&self, name: &str) -> Result<String, VarError> {
+        lookup(self.file, name)
+    }
+
+    /// Reads a variable, as [`env::var_os`] does.
+    fn get_os(&self, name: &str) -> Option<OsString> {
+        match self.file.get(name) {
+            Some(value) => Some(value.into()),
+            None => env::var_os(name),
+        }
+    }
+
+    /// Name of the deployment, from `ENVIRONMENT` or else `DEPLOYMENT`.
+    fn environment(&self) -> Option<String> {
+        self.get("ENVIRONMENT")
+            .or_else(|_| self.get("DEPLOYMENT"))
+            .ok()
+    }
+
+    /// Every variable set, as [`env::vars`] lists them.
+    fn all(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        env::vars()
+            .filter(move |(name, _)| !self.file.contains_key(name))
+            .chain(
+                self.file
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone())),
+            )
+    }
+
+    /// Reads the headers to inject from `INJECT_HEADER_<NAME>` variables, where
+    /// underscores in the name stand for dashes.
+    fn injected_headers(&self) -> Result<Vec<(HeaderName, HeaderValue)>, Box<dyn Error>> {
+        let mut headers = Vec::new();
+
+        for (key, value) in self.all() {
+            if let Some(name) = key.strip_prefix("INJECT_HEADER_") {
+                headers.push((
+                    HeaderName::from_bytes(name.replace('_', "-").as_bytes())?,
+                    HeaderValue::from_str(&value)?,
+                ));
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// Reads the callers' quotas from `CALLER_QUOTAS`, a comma-separated list of
+    /// `caller=requests per second` pairs.
+    fn caller_quotas(&self) -> Result<HashMap<String, u32>, Box<dyn Error>> {
+        let quotas = self.numbers("CALLER_QUOTAS")?;
+
+        if let Some((caller, _)) = quotas.iter().find(|(_, quota)| **quota == 0) {
+            return Err(format!("caller quota for {:?} is 0", caller).into());
+        }
+
+        Ok(quotas)
+    }
+
+    /// Reads the fields to remove from responses from `RESPONSE_REDACTIONS`, a
+    /// comma-separated list of `route=path` pairs where the path's keys are
+    /// separated by dots.
+    fn response_redactions(&self) -> Result<Redactions, Box<dyn Error>> {
+        let mut redactions = HashMap::<_, Vec<_>>::new();
+
+        if let Ok(value) = self.get("RESPONSE_REDACTIONS") {
+            for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let mut parts = pair.splitn(2, '=');
+                let route = parts.next().unwrap_or_default().trim();
+                let path = parts
+                    .next()
+                    .ok_or_else(|| format!("response redaction {:?} isn't route=path", pair))?
+                    .trim()
+                    .split('.')
+                    .map(str::to_owned)
+                    .collect();
+
+                redactions.entry(route.to_owned()).or_default().push(path);
+            }
+        }
+
+        Ok(redactions)
+    }
+
+    /// Parses a comma-separated list of `name=number` pairs.
+    fn numbers(&self, name: &str) -> Result<HashMap<String, u32>, Box<dyn Error>> {
+        let mut numbers = HashMap::new();
+
+        if let Ok(value) = self.get(name) {
+            for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let mut parts = pair.splitn(2, '=');
+                let key = parts.next().unwrap_or_default();
+                let number = parts
+                    .next()
+                    .ok_or_else(|| format!("{} entry {:?} isn't name=number", name, pair))?
+                    .trim()
+                    .parse::<u32>()?;
+
+                numbers.insert(key.trim().to_owned(), number);
+            }
+        }
+
+        Ok(numbers)
+    }
+
+    /// Reads the headers to strip from responses from `STRIP_RESPONSE_HEADERS`,
+    /// where an empty list strips none, or else uses the defaults.
+    fn stripped_headers(&self) -> Result<Vec<HeaderName>, Box<dyn Error>> {
+        match self.get("STRIP_RESPONSE_HEADERS") {
+            Ok(value) if value.trim().is_empty() => Ok(Vec::new()),
+            Ok(_) => Ok(self
+                .header_names("STRIP_RESPONSE_HEADERS")?
+                .unwrap_or_default()),
+            Err(_) => Ok(DEFAULT_STRIPPED_HEADERS
+                .iter()
+                .copied()
+                .map(HeaderName::from_static)
+                .collect()),
+        }
+    }
+
+    /// Names the proxy and its version, and the instance if `INSTANCE_ID` is set,
+    /// such as `twilight-http-proxy/0.1.0 (proxy-2)`.
+    fn server_header(&self) -> Result<HeaderValue, Box<dyn Error>> {
+        let product = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+        match self.get("INSTANCE_ID") {
+            Ok(instance) => {
+                let value = format!("{} ({})", product, instance);
+
+                Ok(HeaderValue::from_str(&value)?)
+            }
+            Err(_) => Ok(HeaderValue::from_static(product)),
+        }
+    }
+
+    /// Whether an environment variable is set to `1` or `true`.
+    fn flag(&self, name: &str) -> bool {
+        self.flag_or(name, false)
+    }
+
+    /// Like [`Vars::flag`], but with a default for when the variable is unset or isn't
+    /// `0`, `1`, `false` or `true`.
+    fn flag_or(&self, name: &str, default: bool) -> bool {
+        match self.get(name).as_deref() {
+            Ok("1") | Ok("true") => true,
+            Ok("0") | Ok("false") => false,
+            _ => default,
+        }
+    }
+
+    /// Parses the base URL of an API to send requests to instead of Discord, such
+    /// as `http://proxy:3000/discord`, returning its host with any path prefix,
+    /// which the API's paths are appended to, and whether it's plain HTTP.
+    fn upstream_host(&self, name: &str) -> Result<Option<(String, bool)>, Box<dyn Error>> {
+        match self.get(name) {
+            Ok(url) => {
+                let uri = url.parse::<Uri>()?;
+                let host = uri
+                    .authority()
+                    .ok_or_else(|| format!("{} has no host", name))?;
+
+                if uri.query().is_some() {
+                    return Err(format!("{} can't have a query", name).into());
+                }
+
+                let base = format!("{}{}", host, uri.path().trim_end_matches('/'));
+
+                Ok(Some((base, uri.scheme_str() == Some("http"))))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Parses a comma-separated list of header names.
+    fn header_names(&self, name: &str) -> Result<Option<Vec<HeaderName>>, Box<dyn Error>> {
+        match self.get(name) {
+            Ok(value) => Ok(Some(
+                value
+                    .split(',')
+                    .map(|name| HeaderName::from_bytes(name.trim().as_bytes()))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Parses a comma-separated list of methods, in any case.
+    fn methods(&self, name: &str) -> Result<Option<Vec<Method>>, Box<dyn Error>> {
+        match self.get(name) {
+            Ok(value) => Ok(Some(
+                value
+                    .split(',')
+                    .map(|method| Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes()))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Parses an environment variable, falling back to a default when it's unset.
+    fn var<T>(&self, name: &str, default: T) -> Result<T, Box<dyn Error>>
+    where
+        T: FromStr,
+        T::Err: Error + 'static,
+    {
+        match self.get(name) {
+            Ok(value) => Ok(value.parse()?),
+            Err(_) => Ok(default),
+        }
+    }
+
+    /// Parses an environment variable where being unset or `0` means "disabled".
+    fn optional<T>(&self, name: &str) -> Result<Option<T>, Box<dyn Error>>
+    where
+        T: Default + FromStr + PartialEq,
+        T::Err: Error + 'static,
+    {
+        let value = self.var(name, T::default())?;
+
+        if value == T::default() {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test sets variables of its own, since tests run in parallel.
+
+    /// The environment alone, without a config file.
+    fn env_only() -> Vars<'static> {
+        Vars {
+            file: startup_file(),
+        }
+    }
+
+    #[test]
+    fn parses_numbers() {
+        env::set_var("CONFIG_TEST_NUMBERS", " Guild = 3,Gateway=1,, ");
+        let parsed = env_only().numbers("CONFIG_TEST_NUMBERS").unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["Guild"], 3);
+        assert_eq!(parsed["Gateway"], 1);
+        assert!(env_only().numbers("CONFIG_TEST_UNSET").unwrap().is_empty());
+
+        env::set_var("CONFIG_TEST_NUMBERS_MISSING", "Guild");
+        assert!(env_only().numbers("CONFIG_TEST_NUMBERS_MISSING").is_err());
+
+        env::set_var("CONFIG_TEST_NUMBERS_NEGATIVE", "Guild=-1");
+        assert!(env_only().numbers("CONFIG_TEST_NUMBERS_NEGATIVE").is_err());
+    }
+
+    #[test]
+    fn parses_header_names() {
+        env::set_var(
+            "CONFIG_TEST_HEADER_NAMES",
+            "X-RateLimit-Remaining, retry-after",
+        );
+        let names = env_only()
+            .header_names("CONFIG_TEST_HEADER_NAMES")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(names, ["x-ratelimit-remaining", "retry-after"]);
+        assert_eq!(env_only().header_names("CONFIG_TEST_UNSET").unwrap(), None);
+
+        env::set_var("CONFIG_TEST_HEADER_NAMES_INVALID", "retry after");
+        assert!(env_only()
+            .header_names("CONFIG_TEST_HEADER_NAMES_INVALID")
+            .is_err());
+    }
+
+    #[test]
+    fn parses_methods() {
+        env::set_var("CONFIG_TEST_METHODS", "get, Post");
+        let parsed = env_only().methods("CONFIG_TEST_METHODS").unwrap().unwrap();
+
+        assert_eq!(parsed, [Method::GET, Method::POST]);
+        assert_eq!(env_only().methods("CONFIG_TEST_UNSET").unwrap(), None);
+
+        env::set_var("CONFIG_TEST_METHODS_INVALID", "GET POST");
+        assert!(env_only().methods("CONFIG_TEST_METHODS_INVALID").is_err());
+    }
+
+    #[test]
+    fn loads_files() {
+        let path = env::temp_dir().join(format!("config-test-{}.env", std::process::id()));
+        fs::write(
+            &path,
+            "# comment\n\nCONFIG_TEST_FILE_A = 1\nCONFIG_TEST_FILE_B=\"two words\"\n",
+        )
+        .unwrap();
+
+        let loaded = load_file(&path);
+        fs::write(&path, "CONFIG_TEST_FILE_A\n").unwrap();
+        let invalid = load_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["CONFIG_TEST_FILE_A"], "1");
+        assert_eq!(loaded["CONFIG_TEST_FILE_B"], "two words");
+        assert!(env::var_os("CONFIG_TEST_FILE_A").is_none());
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn reads_files_over_the_environment() {
+        env::set_var("CONFIG_TEST_OVER_ENV", "env");
+        env::set_var("CONFIG_TEST_OVER_FILE", "env");
+        let file = vec![("CONFIG_TEST_OVER_FILE".to_owned(), "file".to_owned())]
+            .into_iter()
+            .collect();
+
+        assert_eq!(lookup(&file, "CONFIG_TEST_OVER_ENV").as_deref(), Ok("env"));
+        assert_eq!(
+            lookup(&file, "CONFIG_TEST_OVER_FILE").as_deref(),
+            Ok("file")
+        );
+        assert!(lookup(&file, "CONFIG_TEST_UNSET").is_err());
+        assert_eq!(env::var("CONFIG_TEST_OVER_FILE").as_deref(), Ok("env"));
+
+        let file = vec![("MAX_BODY_SIZE".to_owned(), "1234".to_owned())]
+            .into_iter()
+            .collect();
+        let config = Config::from_env_with(&file).unwrap();
+
+        assert_eq!(config.max_body_size, Some(1234));
+    }
+
+    #[test]
+    fn reloads_keep_startup_settings() {
+        let running = Config::from_env().unwrap();
+        let mut wanted = running.clone();
+        wanted.max_connections = Some(10);
+        wanted.single_flight = !running.single_flight;
+        wanted.log_ratelimits = !running.log_ratelimits;
+
+        let applied = wanted.keep_startup_settings(&running);
+
+        assert_eq!(applied.max_connections, running.max_connections);
+        assert_eq!(applied.single_flight, running.single_flight);
+        assert_eq!(applied.log_ratelimits, !running.log_ratelimits);
+    }
+
+    #[test]
+    fn parses_upstream_base_urls() {
+        let parse = |url| {
+            env::set_var("CONFIG_TEST_UPSTREAM", url);
+
+            env_only().upstream_host("CONFIG_TEST_UPSTREAM")
+        };
+
+        assert_eq!(
+            parse("https://discord.example").unwrap(),
+            Some(("discord.example".to_owned(), false))
+        );
+        assert_eq!(
+            parse("http://proxy:3000/discord/").unwrap(),
+            Some(("proxy:3000/discord".to_owned(), true))
+        );
+        assert!(parse("http://proxy:3000/discord?x=1").is_err());
+        assert!(parse("/discord").is_err());
+        assert_eq!(env_only().upstream_host("CONFIG_TEST_UNSET").unwrap(), None);
+    }
+}
@@ -1,15 +1,273 @@
-use http::{Error as HttpError, Uri};
-use hyper::Error as HyperError;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    Error as HttpError, HeaderValue, StatusCode, Uri,
+};
+use hyper::{Body, Error as HyperError, Response};
+use serde_json::{json, Error as JsonError};
 use snafu::Snafu;
+use std::{env::VarError, io::Error as IoError, path::PathBuf, time::Duration};
+use tracing_subscriber::reload::Error as ReloadError;
 use twilight_http::{error::Error as TwilightError, routing::PathParseError};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub(crate)))]
 pub enum RequestError {
+    #[snafu(display("X-Act-As-Token needs the caller's own Authorization too"))]
+    ActAsWithoutAuthorization,
+    #[snafu(display("X-Act-As-Token needs an authorization hook to approve it"))]
+    ActAsWithoutHook,
+    #[snafu(display("missing or incorrect admin secret"))]
+    AdminUnauthorized,
+    #[snafu(display("authorization hook didn't answer within {}ms", timeout_ms))]
+    AuthHookTimedOut { timeout_ms: u64 },
+    #[snafu(display("too many requests are waiting on the {} ratelimit", route))]
+    BucketQueueFull { route: &'static str },
+    BuildingAuthHookRequest { source: HttpError },
+    BuildingCdnUri { source: HttpError },
+    #[snafu(display("authorization hook can't be reached: {}", source))]
+    CallingAuthHook { source: HyperError },
     ChunkingRequest { source: HyperError },
-    InvalidPath { source: PathParseError },
-    MakingResponseBody { source: HttpError },
+    Compressing { source: IoError },
+    #[snafu(display("no response within the {}ms deadline", deadline_ms))]
+    DeadlineExceeded { deadline_ms: u64 },
+    #[snafu(display("unsupported expectation: {:?}", expectation))]
+    ExpectationFailed { expectation: String },
+    #[snafu(display("not authorized to make this request"))]
+    Forbidden,
+    #[snafu(display("proxy's global limit is used up for {}ms", retry_after.as_millis()))]
+    GlobalLimitExceeded { retry_after: Duration },
+    #[snafu(display("invalid {} parameter: {:?}", name, value))]
+    InvalidAdminParameter { name: &'static str, value: String },
+    #[snafu(display("Authorization header present but invalid"))]
+    InvalidAuthorization,
+    #[snafu(display("invalid batch: {}", reason))]
+    InvalidBatch { reason: String },
+    #[snafu(display("token bot-id segment empty or invalid"))]
+    InvalidBotId,
+    #[snafu(display("invalid deadline: {:?}", value))]
+    InvalidDeadline { value: String },
+    #[snafu(display("cannot override {} with {:?}", method, value))]
+    InvalidMethodOverride { method: String, value: String },
+    #[snafu(display("body isn't valid JSON: {}", source))]
+    InvalidJson { source: JsonError },
+    #[snafu(display("invalid path: {} {}", method, path))]
+    InvalidPath {
+        method: String,
+        path: String,
+        source: PathParseError,
+    },
+    #[snafu(display("token query parameter isn't a valid header value"))]
+    InvalidQueryToken,
     MethodNotAllowed { method: String },
     NoPath { uri: Uri },
+    #[snafu(display("body is longer than {} bytes", limit))]
+    PayloadTooLarge { limit: usize },
+    #[snafu(display("caller's quota is used up for {}ms", retry_after.as_millis()))]
+    QuotaExceeded { retry_after: Duration },
+    ReadingResponse { source: HyperError },
+    ReloadingLogFilter { source: ReloadError },
     RequestIssue { source: TwilightError },
+    #[snafu(display("body wasn't received within {}ms", timeout_ms))]
+    RequestTimeout { timeout_ms: u64 },
+    RequestingCdn { source: HyperError },
+    #[snafu(display("{} {} requests are already in flight", limit, route))]
+    RouteConcurrency { limit: u32, route: &'static str },
+    #[snafu(display("too busy for {} requests", route))]
+    Shed { route: &'static str },
+    #[snafu(display("{} connections are already open", limit))]
+    TooManyConnections { limit: usize },
+    #[snafu(display("more than {} tokens used on this connection", limit))]
+    TooManyTokens { limit: usize },
+    #[snafu(display("no admin endpoint at {}", path))]
+    UnknownAdminEndpoint { path: String },
+    #[snafu(display("route of {} isn't known to the proxy", path))]
+    UnknownRoute { path: String },
+    #[snafu(display("uri is {} bytes long, the limit is {}", length, limit))]
+    UriTooLong { length: usize, limit: usize },
+    #[snafu(display("unsupported content type: {:?}", content_type))]
+    UnsupportedMediaType { content_type: String },
+}
+
+impl RequestError {
+    /// Status code returned to the client when this error ends a request.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::ActAsWithoutAuthorization | Self::AdminUnauthorized | Self::InvalidBotId => {
+                StatusCode::UNAUTHORIZED
+            }
+            Self::ActAsWithoutHook
+            | Self::BuildingCdnUri { .. }
+            | Self::ChunkingRequest { .. }
+            | Self::InvalidAdminParameter { .. }
+            | Self::InvalidAuthorization
+            | Self::InvalidBatch { .. }
+            | Self::InvalidDeadline { .. }
+            | Self::InvalidJson { .. }
+            | Self::InvalidMethodOverride { .. }
+            | Self::InvalidPath { .. }
+            | Self::InvalidQueryToken
+            | Self::NoPath { .. } => StatusCode::BAD_REQUEST,
+            Self::AuthHookTimedOut { .. } | Self::DeadlineExceeded { .. } => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            Self::ExpectationFailed { .. } => StatusCode::EXPECTATION_FAILED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::BuildingAuthHookRequest { .. }
+            | Self::Compressing { .. }
+            | Self::ReloadingLogFilter { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            Self::CallingAuthHook { .. }
+            | Self::ReadingResponse { .. }
+            | Self::RequestIssue { .. }
+            | Self::RequestingCdn { .. } => StatusCode::BAD_GATEWAY,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RequestTimeout { .. } => StatusCode::REQUEST_TIMEOUT,
+            Self::GlobalLimitExceeded { .. }
+            | Self::QuotaExceeded { .. }
+            | Self::TooManyTokens { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::BucketQueueFull { .. }
+            | Self::RouteConcurrency { .. }
+            | Self::Shed { .. }
+            | Self::TooManyConnections { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UnknownAdminEndpoint { .. } => StatusCode::NOT_FOUND,
+            Self::UnknownRoute { .. } => StatusCode::NOT_IMPLEMENTED,
+            Self::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::UriTooLong { .. } => StatusCode::URI_TOO_LONG,
+        }
+    }
+
+    /// Which guard turned the request away, if this error is a rejection rather
+    /// than a failure.
+    #[cfg(feature = "expose-metrics")]
+    pub fn rejection(&self) -> Option<&'static str> {
+        match self {
+            Self::ActAsWithoutAuthorization => Some("act_as_without_authorization"),
+            Self::ActAsWithoutHook => Some("act_as_without_hook"),
+            Self::AdminUnauthorized => Some("admin_unauthorized"),
+            Self::BucketQueueFull { .. } => Some("concurrency"),
+            Self::ExpectationFailed { .. } => Some("expectation_failed"),
+            Self::Forbidden => Some("forbidden"),
+            Self::GlobalLimitExceeded { .. } => Some("global_limit"),
+            Self::InvalidAuthorization => Some("invalid_authorization"),
+            Self::InvalidBotId => Some("invalid_bot_id"),
+            Self::InvalidJson { .. } => Some("invalid_json"),
+            Self::MethodNotAllowed { .. } => Some("method_not_allowed"),
+            Self::PayloadTooLarge { .. } => Some("body_too_large"),
+            Self::QuotaExceeded { .. } => Some("quota"),
+            Self::RequestTimeout { .. } => Some("read_timeout"),
+            Self::RouteConcurrency { .. } => Some("route_concurrency"),
+            Self::Shed { .. } => Some("shed"),
+            Self::TooManyConnections { .. } => Some("too_many_connections"),
+            Self::TooManyTokens { .. } => Some("too_many_tokens"),
+            Self::UnknownRoute { .. } => Some("unknown_route"),
+            Self::UnsupportedMediaType { .. } => Some("unsupported_media_type"),
+            Self::UriTooLong { .. } => Some("uri_too_long"),
+            _ => None,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.to_string()));
+        *response.status_mut() = self.status();
+        self.add_headers(&mut response);
+
+        response
+    }
+
+    /// The error as an RFC 7807 problem document, naming the request's path as
+    /// the occurrence.
+    pub fn into_problem_response(self, instance: &str) -> Response<Body> {
+        let status = self.status();
+        let problem = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason(),
+            "status": status.as_u16(),
+            "detail": self.to_string(),
+            "instance": instance,
+        });
+
+        let mut response = Response::new(Body::from(problem.to_string()));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        self.add_headers(&mut response);
+
+        response
+    }
+
+    /// Adds the headers telling the client how to handle the error.
+    fn add_headers(&self, response: &mut Response<Body>) {
+        let retry_after = match self {
+            Self::GlobalLimitExceeded { retry_after } | Self::QuotaExceeded { retry_after } => {
+                retry_after
+            }
+            _ => return,
+        };
+
+        // Rounded up, so a client waiting this long is sure to have quota.
+        let secs = retry_after.as_millis().div_ceil(1000);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs as u64));
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum TokenError {
+    #[snafu(display("environment variable {} can't be read", name))]
+    ReadingEnv { name: String, source: VarError },
+    #[snafu(display("no token was given"))]
+    Empty,
+    #[snafu(display("token file {} can't be read", path.display()))]
+    ReadingFile { path: PathBuf, source: IoError },
+    #[snafu(display("token source {:?} isn't an env:// or file:// uri", uri))]
+    UnknownSource { uri: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body;
+
+    #[tokio::test]
+    async fn describes_errors_as_problem_documents() {
+        let why = RequestError::QuotaExceeded {
+            retry_after: Duration::from_millis(1500),
+        };
+        let response = why.into_problem_response("/api/v9/gateway");
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        let problem = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank",
+                "title": "Too Many Requests",
+                "status": 429,
+                "detail": RequestError::QuotaExceeded {
+                    retry_after: Duration::from_millis(1500),
+                }
+                .to_string(),
+                "instance": "/api/v9/gateway",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn describes_errors_as_text() {
+        let response = RequestError::InvalidAuthorization.into_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!response.headers().contains_key(CONTENT_TYPE));
+
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(bytes, RequestError::InvalidAuthorization.to_string());
+    }
 }
@@ -0,0 +1,123 @@
+use crate::error::{Compressing, ReadingResponse, RequestError};
+use flate2::{write::GzEncoder, Compression};
+use http::{
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+    HeaderMap, HeaderValue,
+};
+use hyper::{body, Body, Response};
+use snafu::ResultExt;
+use std::io::Write;
+
+/// Whether a request's `Accept-Encoding` allows a gzip response.
+pub fn accepted(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                let q = param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok());
+
+                q == Some(0.0)
+            });
+
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
+}
+
+/// Compresses an uncompressed response body of at least `min_size` bytes.
+/// Anything else is returned as it was.
+pub async fn compress(
+    response: Response<Body>,
+    min_size: usize,
+) -> Result<Response<Body>, RequestError> {
+    if response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+
+    // Bodies known to be small aren't worth buffering.
+    let length = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if length.is_some_and(|length| length < min_size) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body).await.context(ReadingResponse)?;
+
+    if bytes.len() < min_size {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    }
+
+    let mut encoder = GzEncoder::new(Vec::with_capacity(bytes.len() / 4), Compression::fast());
+    encoder.write_all(&bytes).context(Compressing)?;
+    let compressed = encoder.finish().context(Compressing)?;
+
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    Ok(Response::from_parts(parts, Body::from(compressed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(values: &[&str]) -> bool {
+        let mut headers = HeaderMap::new();
+
+        for value in values {
+            headers.append(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        }
+
+        accepted(&headers)
+    }
+
+    #[test]
+    fn reads_accept_encoding() {
+        assert!(accepts(&["gzip"]));
+        assert!(accepts(&["br, GZIP;q=0.5"]));
+        assert!(accepts(&["br", "gzip"]));
+        assert!(accepts(&["gzip;q=0.001"]));
+
+        assert!(!accepts(&[]));
+        assert!(!accepts(&["br, deflate"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["gzip; q=0.0, br"]));
+        assert!(!accepts(&["x-gzip"]));
+    }
+
+    #[tokio::test]
+    async fn compresses_large_bodies_only() {
+        let large = "a".repeat(100);
+        let response = compress(Response::new(Body::from(large.clone())), 50)
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+
+        let compressed = body::to_bytes(response.into_body()).await.unwrap();
+        let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+        decoder.write_all(&compressed).unwrap();
+        assert_eq!(decoder.finish().unwrap(), large.as_bytes());
+
+        let response = compress(Response::new(Body::from("small")), 50)
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,242 @@
+use crate::error::{BuildingAuthHookRequest, CallingAuthHook, RequestError};
+use http::{header::CONTENT_TYPE, Method, Request, Uri};
+use hyper::{
+    client::{Client, HttpConnector},
+    Body,
+};
+use hyper_rustls::HttpsConnector;
+use serde_json::json;
+use snafu::ResultExt;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// What the authorization hook is told about a request.
+#[derive(Debug, Hash)]
+pub struct Attempt<'a> {
+    /// The caller's key, from the configured header.
+    pub key: Option<&'a str>,
+    /// The bot the request is made as, if its token names one.
+    pub bot_id: Option<u64>,
+    /// Whether the bot's token was given in `X-Act-As-Token` rather than the
+    /// caller's own `Authorization`.
+    pub act_as: bool,
+    /// Whether the caller gave its own `Authorization`.
+    pub has_primary: bool,
+    /// The bot the caller's own `Authorization` names, which is the one the
+    /// request is made as unless it acts as another.
+    pub primary_bot_id: Option<u64>,
+    pub route: &'a str,
+    pub method: &'a str,
+}
+
+/// An external service asked whether each request may be forwarded.
+pub struct AuthHook {
+    /// When each recently approved attempt's approval runs out, by its hash.
+    approved: Mutex<HashMap<u64, Instant>>,
+    /// Longest the hook is waited on.
+    timeout: Duration,
+    ttl: Duration,
+    uri: Uri,
+}
+
+impl AuthHook {
+    pub fn new(uri: Uri, ttl: Duration, timeout: Duration) -> Self {
+        Self {
+            approved: Mutex::new(HashMap::new()),
+            timeout,
+            ttl,
+            uri,
+        }
+    }
+
+    /// Asks the hook about an attempt, unless it was approved recently. Anything
+    /// but a 2xx from the hook refuses it, and a hook that takes too long fails
+    /// the request.
+    pub async fn check(
+        &self,
+        client: &Client<HttpsConnector<HttpConnector>>,
+        attempt: &Attempt<'_>,
+    ) -> Result<(), RequestError> {
+        let hash = approval_key(attempt);
+
+        if self.is_approved(hash) {
+            return Ok(());
+        }
+
+        let body = json!({
+            "act_as": attempt.act_as,
+            "bot_id": attempt.bot_id.map(|id| id.to_string()),
+            "has_primary": attempt.has_primary,
+            "key": attempt.key,
+            "method": attempt.method,
+            "primary_bot_id": attempt.primary_bot_id.map(|id| id.to_string()),
+            "route": attempt.route,
+        });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .context(BuildingAuthHookRequest)?;
+
+        let response = tokio::time::timeout(self.timeout, client.request(request))
+            .await
+            .map_err(|_| RequestError::AuthHookTimedOut {
+                timeout_ms: self.timeout.as_millis() as u64,
+            })?
+            .context(CallingAuthHook)?;
+
+        if !response.status().is_success() {
+            return Err(RequestError::Forbidden);
+        }
+
+        if self.ttl > Duration::from_secs(0) {
+            let now = Instant::now();
+            let mut approved = self.approved.lock().unwrap_or_else(PoisonError::into_inner);
+            approved.retain(|_, expires| *expires > now);
+            approved.insert(hash, now + self.ttl);
+        }
+
+        Ok(())
+    }
+
+    fn is_approved(&self, hash: u64) -> bool {
+        self.approved
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&hash)
+            .is_some_and(|expires| *expires > Instant::now())
+    }
+}
+
+/// What approvals are remembered by, which covers everything the hook was told.
+fn approval_key(attempt: &Attempt<'_>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    attempt.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{Response, StatusCode};
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Server,
+    };
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    const ATTEMPT: Attempt<'static> = Attempt {
+        key: Some("caller"),
+        bot_id: Some(1),
+        act_as: false,
+        has_primary: true,
+        primary_bot_id: Some(1),
+        route: "Gateway",
+        method: "GET",
+    };
+
+    /// Runs a hook that answers each call with `status` after `delay`, and
+    /// counts how often it was called.
+    fn hook(status: StatusCode, delay: Duration) -> (Uri, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            move |_| {
+                let calls = Arc::clone(&counted);
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+
+                        async move {
+                            tokio::time::sleep(delay).await;
+
+                            Response::builder().status(status).body(Body::empty())
+                        }
+                    }))
+                }
+            },
+        ));
+        let uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        (uri, calls)
+    }
+
+    fn client() -> Client<HttpsConnector<HttpConnector>> {
+        Client::builder().build(HttpsConnector::with_native_roots())
+    }
+
+    #[tokio::test]
+    async fn refuses_what_the_hook_refuses() {
+        let (uri, calls) = hook(StatusCode::FORBIDDEN, Duration::from_secs(0));
+        let hook = AuthHook::new(uri, Duration::from_secs(60), Duration::from_secs(5));
+
+        for _ in 0..2 {
+            let result = hook.check(&client(), &ATTEMPT).await;
+            assert!(matches!(result, Err(RequestError::Forbidden)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn remembers_approvals() {
+        let (uri, calls) = hook(StatusCode::NO_CONTENT, Duration::from_secs(0));
+        let hook = AuthHook::new(uri, Duration::from_secs(60), Duration::from_secs(5));
+
+        hook.check(&client(), &ATTEMPT).await.unwrap();
+        hook.check(&client(), &ATTEMPT).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let other = Attempt {
+            route: "ChannelsId",
+            ..ATTEMPT
+        };
+        hook.check(&client(), &other).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hook_that_takes_too_long() {
+        let (uri, _) = hook(StatusCode::NO_CONTENT, Duration::from_secs(10));
+        let hook = AuthHook::new(uri, Duration::from_secs(60), Duration::from_millis(50));
+
+        let result = hook.check(&client(), &ATTEMPT).await;
+        assert!(matches!(
+            result,
+            Err(RequestError::AuthHookTimedOut { timeout_ms: 50 })
+        ));
+    }
+
+    #[test]
+    fn approves_acting_as_a_bot_for_one_primary_at_a_time() {
+        let attempt = Attempt {
+            key: None,
+            bot_id: Some(1),
+            act_as: true,
+            has_primary: true,
+            primary_bot_id: Some(2),
+            route: "Gateway",
+            method: "GET",
+        };
+        let other_primary = Attempt {
+            primary_bot_id: Some(3),
+            ..attempt
+        };
+
+        assert_ne!(approval_key(&attempt), approval_key(&other_primary));
+    }
+}
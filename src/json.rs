@@ -0,0 +1,186 @@
+use crate::error::{ReadingResponse, RequestError};
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderValue,
+};
+use hyper::{body, Body, Response};
+use serde_json::Value;
+use snafu::ResultExt;
+
+/// Substrings of object keys whose values are hidden when bodies are logged.
+const SECRET_KEYS: &[&str] = &["password", "secret", "token"];
+
+/// Replaces the value of every key that looks like it holds a secret, at any
+/// depth.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                    *value = Value::String("[redacted]".into());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes the fields at each of `paths` from a JSON response body, leaving
+/// other responses as they were.
+///
+/// A path is a list of object keys, where `*` stands for every element of an
+/// array or every value of an object.
+pub async fn remove_fields(
+    response: Response<Body>,
+    paths: &[Vec<String>],
+) -> Result<Response<Body>, RequestError> {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    if !is_json || response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body).await.context(ReadingResponse)?;
+
+    let mut value = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Ok(Response::from_parts(parts, Body::from(bytes))),
+    };
+
+    for path in paths {
+        remove_path(&mut value, path);
+    }
+
+    let body = value.to_string();
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+fn remove_path(value: &mut Value, path: &[String]) {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    match value {
+        Value::Array(values) if key == "*" => {
+            for value in values {
+                remove_path(value, rest);
+            }
+        }
+        Value::Object(map) if key == "*" && !rest.is_empty() => {
+            for value in map.values_mut() {
+                remove_path(value, rest);
+            }
+        }
+        Value::Object(map) if key == "*" => map.clear(),
+        Value::Object(map) if rest.is_empty() => {
+            map.remove(key);
+        }
+        Value::Object(map) => {
+            if let Some(value) = map.get_mut(key) {
+                remove_path(value, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_secrets_at_any_depth() {
+        let mut value = json!({
+            "content": "hi",
+            "Password": "hunter2",
+            "embeds": [{"access_token": "abc", "title": "t"}],
+            "nested": {"client_secret": {"value": 1}},
+        });
+        redact(&mut value);
+
+        assert_eq!(
+            value,
+            json!({
+                "content": "hi",
+                "Password": "[redacted]",
+                "embeds": [{"access_token": "[redacted]", "title": "t"}],
+                "nested": {"client_secret": "[redacted]"},
+            })
+        );
+    }
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('.').map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn removes_paths() {
+        let mut value = json!({"id": "1", "user": {"email": "a@b.c", "id": "2"}});
+        remove_path(&mut value, &path("user.email"));
+        remove_path(&mut value, &path("missing.key"));
+
+        assert_eq!(value, json!({"id": "1", "user": {"id": "2"}}));
+    }
+
+    #[test]
+    fn removes_paths_through_wildcards() {
+        let mut value = json!([
+            {"user": {"email": "a@b.c", "id": "1"}},
+            {"user": {"email": "d@e.f", "id": "2"}},
+        ]);
+        remove_path(&mut value, &path("*.user.email"));
+
+        assert_eq!(value, json!([{"user": {"id": "1"}}, {"user": {"id": "2"}}]));
+
+        let mut value = json!({"roles": {"1": {"name": "a", "color": 0}}, "keep": {"x": 1}});
+        remove_path(&mut value, &path("roles.*.color"));
+
+        assert_eq!(
+            value,
+            json!({"roles": {"1": {"name": "a"}}, "keep": {"x": 1}})
+        );
+
+        remove_path(&mut value, &path("roles.*"));
+        assert_eq!(value, json!({"roles": {}, "keep": {"x": 1}}));
+    }
+
+    #[tokio::test]
+    async fn removes_fields_from_json_responses_only() {
+        let paths = [path("token")];
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"id":"1","token":"abc"}"#))
+            .unwrap();
+
+        let response = remove_fields(response, &paths).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(bytes, r#"{"id":"1"}"#);
+
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from(r#"{"token":"abc"}"#))
+            .unwrap();
+
+        let response = remove_fields(response, &paths).await.unwrap();
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(bytes, r#"{"token":"abc"}"#);
+    }
+}
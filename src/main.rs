@@ -1,56 +1,434 @@
+#![recursion_limit = "256"]
+// Every handler returns a `RequestError`, which carries twilight's errors as they
+// are.
+#![allow(clippy::result_large_err)]
+
+mod access_log;
+mod admin;
+mod batch;
+mod cache;
+mod config;
 mod error;
+mod gzip;
+mod hook;
+mod json;
+mod proxy_protocol;
+mod queue;
+mod quota;
+mod ratelimits;
+mod recent;
+mod stats;
+mod token;
+mod upstream;
+mod usage;
 
-use error::{ChunkingRequest, InvalidPath, RequestError, RequestIssue};
-use http::request::Parts;
+use cache::{CachedResponse, ResponseCache};
+use config::{Config, ErrorFormat, HeaderPolicy};
+use error::{
+    BuildingCdnUri, ChunkingRequest, InvalidPath, RequestError, RequestIssue, RequestingCdn,
+};
+use hook::{Attempt, AuthHook};
+use http::{
+    header::{
+        HeaderName, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, EXPECT,
+        HOST, PRAGMA, RETRY_AFTER, SERVER, TRANSFER_ENCODING, USER_AGENT,
+    },
+    request::Parts,
+    HeaderMap, HeaderValue, StatusCode, Uri,
+};
 use hyper::{
-    body::Body,
-    server::{conn::AddrStream, Server},
-    service, Request, Response,
+    body::{Body, HttpBody},
+    client::{Client as HyperClient, HttpConnector},
+    server::{
+        conn::{AddrStream, Http},
+        Server,
+    },
+    service::{self, Service},
+    Request, Response,
 };
+use hyper_rustls::HttpsConnector;
+use queue::BucketQueues;
+use quota::{GlobalLimit, Quotas};
+use ratelimits::Ratelimits;
+use recent::RecentRequests;
+use rand::Rng;
 use snafu::ResultExt;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use stats::Stats;
 use std::{
+    borrow::Cow,
     convert::TryFrom,
     env,
     error::Error,
-    net::{IpAddr, SocketAddr},
+    future::Future,
+    hash::{Hash, Hasher},
+    io::Result as IoResult,
+    fs,
+    net::{IpAddr, SocketAddr, SocketAddrV6, TcpListener},
+    process,
     str::FromStr,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tracing::{debug, error, info, trace};
+use token::{BotIds, TokenSource};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
 use tracing_log::LogTracer;
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::{filter::ParseError, fmt, reload, EnvFilter};
 use twilight_http::{
-    client::Client, request::Request as TwilightRequest, routing::Path, API_VERSION,
+    client::Client,
+    request::Request as TwilightRequest,
+    routing::{Path, PathParseError},
+    API_VERSION,
 };
+use upstream::{Failover, Rebuilding, RoundRobin, Upstream};
+use usage::TokenUsage;
 
 #[cfg(feature = "expose-metrics")]
-use std::{future::Future, pin::Pin, time::Instant};
+use std::pin::Pin;
 
 #[cfg(feature = "expose-metrics")]
 use lazy_static::lazy_static;
 #[cfg(feature = "expose-metrics")]
-use prometheus::{HistogramOpts, HistogramVec, Registry, TextEncoder, Encoder};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
 use twilight_http::request::Method;
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
     static ref METRIC_KEY: String =
-        env::var("METRIC_KEY").unwrap_or_else(|_| "twilight_http_proxy".into());
+        config::lookup(config::startup_file(), "METRIC_KEY")
+            .unwrap_or_else(|_| "twilight_http_proxy".into());
 
     static ref REGISTRY: Registry = Registry::new();
 
     static ref HISTOGRAM: HistogramVec = HistogramVec::new(
-        HistogramOpts::new(METRIC_KEY.as_str(), "Response Times"),
+        histogram_opts(METRIC_KEY.as_str(), "Response Times"),
         &["method", "route", "status"]
     ).unwrap();
+
+    static ref BODY_HISTOGRAM: HistogramVec = HistogramVec::new(
+        histogram_opts(format!("{}_body", *METRIC_KEY), "Request Body Buffering Times"),
+        &["method", "route"]
+    ).unwrap();
+
+    static ref REJECTIONS: IntCounterVec = IntCounterVec::new(
+        metric_opts(format!("{}_rejected", *METRIC_KEY), "Requests Rejected By A Guard"),
+        &["reason"]
+    ).unwrap();
+
+    static ref BOT_REQUESTS: IntCounterVec = IntCounterVec::new(
+        metric_opts(format!("{}_bot_requests", *METRIC_KEY), "Requests Made As Each Bot"),
+        &["bot"]
+    ).unwrap();
+
+    static ref GLOBAL_THROTTLED: IntGauge = IntGauge::with_opts(
+        metric_opts(format!("{}_global_throttled", *METRIC_KEY), "Whether Requests Are Held To GLOBAL_MAX_RPS")
+    ).unwrap();
+
+    static ref FAILOVERS: IntCounter = IntCounter::with_opts(
+        metric_opts(format!("{}_failovers", *METRIC_KEY), "Requests Sent To The Fallback Upstream")
+    ).unwrap();
+
+    static ref UPSTREAM_RESPONSES: IntCounterVec = IntCounterVec::new(
+        metric_opts(format!("{}_upstream_responses", *METRIC_KEY), "Upstream Responses By Status Class"),
+        &["class"]
+    ).unwrap();
+
+    static ref SHADOW_RESULTS: IntCounterVec = IntCounterVec::new(
+        metric_opts(format!("{}_shadow", *METRIC_KEY), "Shadowed Requests By Outcome"),
+        &["outcome"]
+    ).unwrap();
+
+    static ref BUILD_INFO: IntGaugeVec = IntGaugeVec::new(
+        metric_opts(format!("{}_build_info", *METRIC_KEY), "Build Information"),
+        &["version", "twilight_version", "api_version"]
+    ).unwrap();
+
+    static ref CONNECTIONS: IntGauge = IntGauge::with_opts(
+        metric_opts(format!("{}_connections", *METRIC_KEY), "Open Inbound Connections")
+    ).unwrap();
+
+    static ref QUEUE_DEPTH: IntGaugeVec = IntGaugeVec::new(
+        metric_opts(format!("{}_queued", *METRIC_KEY), "Requests Waiting On A Ratelimit Bucket"),
+        &["route"]
+    ).unwrap();
+
+    static ref RETIRED_CLIENTS: IntGauge = IntGauge::with_opts(
+        metric_opts(format!("{}_retired_clients", *METRIC_KEY), "Replaced Clients Not Yet Released")
+    ).unwrap();
+}
+
+/// Size of the last metrics response, used to size the next one's buffer.
+#[cfg(feature = "expose-metrics")]
+static METRICS_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Options for a histogram, labeled with the deployment's name if one is set.
+#[cfg(feature = "expose-metrics")]
+fn histogram_opts(name: impl Into<String>, help: &str) -> HistogramOpts {
+    let opts = HistogramOpts::new(name, help);
+
+    match config::environment() {
+        Some(environment) => opts.const_label("environment", environment),
+        None => opts,
+    }
+}
+
+/// Version of twilight-http the proxy was built with, as locked in `Cargo.lock`.
+#[cfg(feature = "expose-metrics")]
+fn twilight_version() -> &'static str {
+    let lock = include_str!("../Cargo.lock");
+
+    lock.split("[[package]]")
+        .find(|package| package.contains("name = \"twilight-http\"\n"))
+        .and_then(|package| package.split("version = \"").nth(1))
+        .and_then(|rest| rest.split('"').next())
+        .unwrap_or("unknown")
+}
+
+/// Options for a counter or gauge, labeled like [`histogram_opts`].
+#[cfg(feature = "expose-metrics")]
+fn metric_opts(name: impl Into<String>, help: &str) -> Opts {
+    let opts = Opts::new(name, help);
+
+    match config::environment() {
+        Some(environment) => opts.const_label("environment", environment),
+        None => opts,
+    }
+}
+
+/// Longest a connection may take to send its PROXY protocol header when no
+/// inbound idle timeout is configured.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes reserved for a request body before any of it is read, when no
+/// body size limit is configured.
+const MAX_BODY_RESERVATION: usize = 8 * 1024 * 1024;
+
+/// How many times `/ready` looks at the clients' lock, 10ms apart.
+const READY_ATTEMPTS: usize = 5;
+
+/// Header with the token to send a request as in place of the caller's own,
+/// once the authorization hook has approved it.
+const ACT_AS_TOKEN: &str = "x-act-as-token";
+
+/// Header naming a request whose successful response is repeated to retries.
+const IDEMPOTENCY_KEY: &str = "x-idempotency-key";
+
+/// Header giving the longest a client will wait for a response, in milliseconds.
+const REQUEST_DEADLINE: &str = "x-request-deadline-ms";
+
+/// Handle for replacing the log filter while the proxy runs.
+type LogFilter = reload::Handle<EnvFilter, tracing_subscriber::Registry>;
+
+/// Handles shared by every connection.
+struct State {
+    auth_hook: Option<AuthHook>,
+    /// Recently used tokens' bot ids, if they're cached.
+    bot_ids: Option<BotIds>,
+    /// Requests waiting on each ratelimit bucket, if their number is limited.
+    buckets: Option<BucketQueues>,
+    /// Client for requests marked with the canary header.
+    canary: Option<Box<dyn Upstream>>,
+    /// Plain HTTP client for upstreams twilight doesn't route to, such as the CDN.
+    cdn: HyperClient<HttpsConnector<HttpConnector>>,
+    client: Box<dyn Upstream>,
+    /// The settings in effect, which are replaced when they're reloaded.
+    config: RwLock<Arc<Config>>,
+    /// Connections that may still be accepted, if their number is limited.
+    connections: Option<Arc<Semaphore>>,
+    /// The bot the global token belongs to.
+    default_bot_id: Option<u64>,
+    /// Recent `GET /gateway/bot` responses, keyed by the token's hash.
+    gateway_bot: Option<ResponseCache>,
+    /// Requests that may still be sent to Discord this second, whoever makes
+    /// them, if their number is limited.
+    global_limit: Option<GlobalLimit>,
+    /// Successful responses to requests with an idempotency key, keyed by the
+    /// key and the request.
+    idempotency: Option<ResponseCache>,
+    log_filter: LogFilter,
+    /// Whether proxied requests get the maintenance response, which starts as
+    /// `MAINTENANCE_MODE` and can be switched through the admin endpoints.
+    maintenance: AtomicBool,
+    /// Requests left in each caller's quota, if any callers have one.
+    quotas: Option<Quotas>,
+    /// Ratelimits Discord reported for each bot, kept for the admin endpoints.
+    ratelimits: Option<Ratelimits>,
+    /// Summaries of the latest forwarded requests, if they're recorded.
+    recent: Option<RecentRequests>,
+    /// Requests that may still be in flight for each route with a limit, by
+    /// its name in the metrics.
+    route_limits: HashMap<String, Semaphore>,
+    /// Client that `GET` requests are copied to, for comparing its responses
+    /// with Discord's.
+    shadow: Option<Arc<dyn Upstream>>,
+    /// `GET` requests in flight, for identical ones to wait on instead of
+    /// repeating.
+    ///
+    /// They're keyed by the bot's id and the path and query. The hash of the
+    /// token is part of the key too, since anyone can make up a token claiming
+    /// a bot's id, so only requests with the same token share a response.
+    single_flight: Option<ResponseCache>,
+    stats: Stats,
+    /// When each token was first and last used, kept for the admin endpoints.
+    token_usage: Option<TokenUsage>,
+}
+
+impl State {
+    /// Builds the state for a configuration, sending requests through `client`,
+    /// without a canary or shadow upstream or a token of its own.
+    fn new(config: Config, client: Box<dyn Upstream>, log_filter: LogFilter) -> Self {
+        Self {
+            auth_hook: config
+                .auth_hook_url
+                .clone()
+                .map(|uri| AuthHook::new(uri, config.auth_hook_cache, config.auth_hook_timeout)),
+            bot_ids: config.auth_cache_size.map(BotIds::new),
+            buckets: config.max_bucket_queue.map(BucketQueues::new),
+            canary: None,
+            cdn: HyperClient::builder().build(HttpsConnector::with_native_roots()),
+            client,
+            connections: config
+                .max_connections
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            default_bot_id: None,
+            gateway_bot: config
+                .gateway_bot_cache
+                .map(|ttl| ResponseCache::new(ttl).lifetime(session_start_reset)),
+            global_limit: config.global_max_rps.map(GlobalLimit::new),
+            idempotency: config
+                .idempotency_ttl
+                .map(|ttl| ResponseCache::new(ttl).capacity(config.idempotency_max_keys)),
+            log_filter,
+            maintenance: AtomicBool::new(config.maintenance_mode),
+            quotas: if config.caller_quotas.is_empty() && config.default_caller_quota.is_none() {
+                None
+            } else {
+                Some(Quotas::new(
+                    config.caller_quotas.clone(),
+                    config.default_caller_quota,
+                ))
+            },
+            ratelimits: config.admin_secret.as_ref().map(|_| Ratelimits::default()),
+            recent: config.record_requests.map(RecentRequests::new),
+            route_limits: config
+                .route_concurrency
+                .iter()
+                .map(|(route, limit)| (route.clone(), Semaphore::new(*limit as usize)))
+                .collect(),
+            shadow: None,
+            single_flight: if config.single_flight {
+                Some(ResponseCache::new(Duration::from_secs(0)))
+            } else {
+                None
+            },
+            stats: Stats::new(),
+            token_usage: config.admin_secret.as_ref().map(|_| TokenUsage::default()),
+            config: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    fn config(&self) -> Arc<Config> {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// State for a single inbound connection.
+struct Connection {
+    /// Address of the client, which is the load balancer's unless it passed on
+    /// the real one with the PROXY protocol.
+    peer: SocketAddr,
+    /// The connection's place among those allowed at once, if their number is
+    /// limited.
+    _permit: Option<OwnedSemaphorePermit>,
+    /// Number of requests being handled on the connection.
+    handling: AtomicUsize,
+    /// When the connection's last request was handled, or else when it opened.
+    idle_since: Mutex<Instant>,
+    /// Hashes of the distinct tokens used on the connection.
+    tokens: Mutex<HashSet<u64>>,
+}
+
+impl Connection {
+    fn new(peer: SocketAddr, permit: Option<OwnedSemaphorePermit>) -> Self {
+        #[cfg(feature = "expose-metrics")]
+        CONNECTIONS.inc();
+
+        Self {
+            handling: AtomicUsize::new(0),
+            idle_since: Mutex::new(Instant::now()),
+            peer,
+            _permit: permit,
+            tokens: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Marks a request as being handled until the returned guard is dropped.
+    fn start_request(&self) -> Handling<'_> {
+        self.handling.fetch_add(1, Ordering::Relaxed);
+
+        Handling(self)
+    }
+
+    /// How long the connection has gone without handling a request, which is
+    /// no time at all while it's handling one.
+    fn idle_for(&self) -> Duration {
+        if self.handling.load(Ordering::Relaxed) > 0 {
+            return Duration::from_secs(0);
+        }
+
+        self.idle_since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .elapsed()
+    }
+}
+
+/// Guard for a request counted by [`Connection::start_request`].
+struct Handling<'a>(&'a Connection);
+
+impl Drop for Handling<'_> {
+    fn drop(&mut self) {
+        *self
+            .0
+            .idle_since
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+        self.0.handling.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        #[cfg(feature = "expose-metrics")]
+        CONNECTIONS.dec();
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     LogTracer::init()?;
 
-    let log_filter_layer =
-        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info"))?;
+    // Variables in the config file take precedence over the environment.
+    let file = match env::var_os("CONFIG_FILE") {
+        Some(path) => config::load_file(path.as_ref())?,
+        None => HashMap::new(),
+    };
+    config::keep_startup_file(file.clone());
+    let config = Config::from_env_with(&file)?;
+
+    let (log_filter_layer, log_filter) = reload::Layer::new(log_filter(None, &config)?);
     let log_fmt_layer = fmt::layer();
 
     let log_subscriber = tracing_subscriber::registry()
@@ -59,211 +437,3512 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     tracing::subscriber::set_global_default(log_subscriber)?;
 
-    let host_raw = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".into());
-    let host = IpAddr::from_str(&host_raw)?;
-    let port = env::var("PORT").unwrap_or_else(|_| "80".into()).parse()?;
+    if config.allow_query_token {
+        warn!("Tokens are accepted in query strings, where they are more likely to be logged");
+    }
+
+    let host_raw = config::lookup(&file, "HOST").unwrap_or_else(|_| "0.0.0.0".into());
+    let port = config::lookup(&file, "PORT")
+        .unwrap_or_else(|_| "80".into())
+        .parse()?;
+
+    let token_source = TokenSource::from_env()?;
+    let tokens = token_source.load()?;
+    let default_bot_id = token::split(&tokens)
+        .next()
+        .and_then(|token| token::derive_bot_id(token).ok());
+    let canary = config
+        .canary_upstream
+        .as_ref()
+        .map(|host| upstream_via(&tokens, Some(host)));
+    let shadow: Option<Arc<dyn Upstream>> = config
+        .shadow_upstream
+        .as_ref()
+        .map(|host| Arc::from(upstream_via(&tokens, Some(host))));
+    let fallback = config.fallback_upstream.clone();
+    let last_tokens = Mutex::new(tokens);
+    let client = Box::new(Rebuilding::new(
+        config.client_max_age,
+        Box::new(move || -> Box<dyn Upstream> {
+            let tokens = reload_tokens(&token_source, &last_tokens);
+
+            match &fallback {
+                Some(host) => Box::new(Failover::new(
+                    upstream(&tokens),
+                    upstream_via(&tokens, Some(host)),
+                )),
+                None => upstream(&tokens),
+            }
+        }),
+    ));
+    let state = Arc::new(State {
+        canary,
+        default_bot_id,
+        shadow,
+        ..State::new(config, client, log_filter)
+    });
 
-    let client = Client::new(env::var("DISCORD_TOKEN")?);
+    let listener = match inherited_listener()? {
+        Some(listener) => {
+            info!("Using the socket passed by systemd");
+
+            listener
+        }
+        None => bind(listen_address(&host_raw, port)?, &state.config())?,
+    };
+    // The port may have been 0, leaving the OS to pick one.
+    let address = listener.local_addr()?;
 
-    let address = SocketAddr::from((host, port));
+    if let Some(path) = &state.config().port_file {
+        tokio::fs::write(path, address.port().to_string()).await?;
+    }
 
     #[cfg(feature = "expose-metrics")]
     REGISTRY.register(Box::new(HISTOGRAM.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(BODY_HISTOGRAM.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(REJECTIONS.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(QUEUE_DEPTH.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(CONNECTIONS.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(SHADOW_RESULTS.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(UPSTREAM_RESPONSES.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    if state.config().fallback_upstream.is_some() {
+        REGISTRY.register(Box::new(FAILOVERS.clone()))?;
+    }
+    #[cfg(feature = "expose-metrics")]
+    if state.global_limit.is_some() {
+        REGISTRY.register(Box::new(GLOBAL_THROTTLED.clone()))?;
+    }
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(BUILD_INFO.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    REGISTRY.register(Box::new(RETIRED_CLIENTS.clone()))?;
+    #[cfg(feature = "expose-metrics")]
+    BUILD_INFO
+        .with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            twilight_version(),
+            API_VERSION.to_string().as_str(),
+        ])
+        .set(1);
+    #[cfg(feature = "expose-metrics")]
+    if state.config().bot_metrics {
+        REGISTRY.register(Box::new(BOT_REQUESTS.clone()))?;
+    }
 
-    // The closure inside `make_service_fn` is run for each connection,
-    // creating a 'service' to handle requests for that specific connection.
-    let service = service::make_service_fn(move |addr: &AddrStream| {
-        debug!("Connection from: {:?}", addr);
-        let client = client.clone();
+    let stats_task = state.config().stats_interval.map(|interval| {
+        let state = state.clone();
 
-        async move {
-            Ok::<_, RequestError>(service::service_fn(move |incoming: Request<Body>| {
-                #[cfg(feature = "expose-metrics")]
-                {
-                    let uri = incoming.uri();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately.
+            ticker.tick().await;
 
-                    if uri.path() == "/metrics" {
-                        handle_metrics()
-                    } else {
-                        Box::pin(handle_request(client.clone(), incoming))
-                    }
-                }
+            loop {
+                ticker.tick().await;
 
-                #[cfg(not(feature = "expose-metrics"))]
-                {
-                    handle_request(client.clone(), incoming)
-                }
-            }))
-        }
+                info!(
+                    "Handled {} requests, {} in flight, up {}s",
+                    state.stats.handled(),
+                    state.stats.in_flight(),
+                    state.stats.uptime().as_secs()
+                );
+            }
+        })
+    });
+
+    let keepalive_task = state.config().keepalive_ping.map(|interval| {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+                state.client.ping().await;
+            }
+        })
     });
 
-    let server = Server::bind(&address).serve(service);
+    #[cfg(unix)]
+    let reload_task = Some(reload_on_hangup(state.clone(), file)?);
+    #[cfg(not(unix))]
+    let reload_task = None;
 
     info!("Listening on http://{}", address);
 
-    if let Err(why) = server.await {
-        error!("Fatal server error: {}", why);
+    // Hyper can't close a connection it's accepted itself for being idle.
+    if state.config().proxy_protocol || state.config().inbound_idle_timeout.is_some() {
+        if let Err(why) = serve_connections(listener, state).await {
+            error!("Fatal server error: {}", why);
+        }
+    } else {
+        let nodelay = state.config().tcp_nodelay;
+        let keepalive = state.config().tcp_keepalive;
+
+        // The closure inside `make_service_fn` is run for each connection,
+        // creating a 'service' to handle requests for that specific connection.
+        let service = service::make_service_fn(move |addr: &AddrStream| {
+            debug!("Connection from: {:?}", addr);
+            // Hyper closes the connection if it can't have a service.
+            let service = connection_permit(&state).map(|permit| {
+                let connection = Arc::new(Connection::new(addr.remote_addr(), permit));
+
+                connection_service(state.clone(), connection)
+            });
+
+            if let Err(why) = &service {
+                debug!("Closing connection from {}: {}", addr.remote_addr(), why);
+            }
+
+            async move { service }
+        });
+
+        let server = Server::from_tcp(listener)?
+            .tcp_nodelay(nodelay)
+            .tcp_keepalive(keepalive)
+            .serve(service);
+
+        if let Err(why) = server.await {
+            error!("Fatal server error: {}", why);
+        }
+    }
+
+    for task in stats_task
+        .into_iter()
+        .chain(keepalive_task)
+        .chain(reload_task)
+    {
+        task.abort();
     }
 
     Ok(())
 }
 
-fn path_name(path: &Path) -> &'static str {
-    match path {
-        Path::ChannelsId(..) => "Channel",
-        Path::ChannelsIdInvites(..) => "Channel invite",
-        Path::ChannelsIdMessages(..) => "Channel message",
-        Path::ChannelsIdMessagesBulkDelete(..) => "Bulk delete message",
-        Path::ChannelsIdMessagesId(..) => "Channel message",
-        Path::ChannelsIdMessagesIdReactions(..) => "Message reaction",
-        Path::ChannelsIdMessagesIdReactionsUserIdType(..) => "Message reaction for user",
-        Path::ChannelsIdPermissionsOverwriteId(..) => "Channel permission override",
-        Path::ChannelsIdPins(..) => "Channel pins",
-        Path::ChannelsIdPinsMessageId(..) => "Specific channel pin",
-        Path::ChannelsIdTyping(..) => "Typing indicator",
-        Path::ChannelsIdWebhooks(..) => "Webhook",
-        Path::Gateway => "Gateway",
-        Path::GatewayBot => "Gateway bot info",
-        Path::Guilds => "Guilds",
-        Path::GuildsId(..) => "Guild",
-        Path::GuildsIdBans(..) => "Guild bans",
-        Path::GuildsIdAuditLogs(..) => "Guild audit logs",
-        Path::GuildsIdBansUserId(..) => "Guild ban for user",
-        Path::GuildsIdChannels(..) => "Guild channel",
-        Path::GuildsIdWidget(..) => "Guild widget",
-        Path::GuildsIdEmojis(..) => "Guild emoji",
-        Path::GuildsIdEmojisId(..) => "Specific guild emoji",
-        Path::GuildsIdIntegrations(..) => "Guild integrations",
-        Path::GuildsIdIntegrationsId(..) => "Specific guild integration",
-        Path::GuildsIdIntegrationsIdSync(..) => "Sync guild integration",
-        Path::GuildsIdInvites(..) => "Guild invites",
-        Path::GuildsIdMembers(..) => "Guild members",
-        Path::GuildsIdMembersId(..) => "Specific guild member",
-        Path::GuildsIdMembersIdRolesId(..) => "Guild member role",
-        Path::GuildsIdMembersMeNick(..) => "Modify own nickname",
-        Path::GuildsIdPreview(..) => "Guild preview",
-        Path::GuildsIdPrune(..) => "Guild prune",
-        Path::GuildsIdRegions(..) => "Guild region",
-        Path::GuildsIdRoles(..) => "Guild roles",
-        Path::GuildsIdRolesId(..) => "Specific guild role",
-        Path::GuildsIdVanityUrl(..) => "Guild vanity invite",
-        Path::GuildsIdWebhooks(..) => "Guild webhooks",
-        Path::InvitesCode => "Invite info",
-        Path::UsersId => "User info",
-        Path::UsersIdConnections => "User connections",
-        Path::UsersIdChannels => "User channels",
-        Path::UsersIdGuilds => "User in guild",
-        Path::UsersIdGuildsId => "Guild from user",
-        Path::VoiceRegions => "Voice region list",
-        Path::WebhooksId(..) => "Webhook",
-        Path::OauthApplicationsMe => "Current application info",
-        _ => "Unknown path!",
-    }
-}
+/// Creates the service handling the requests on a single connection.
+fn connection_service(
+    state: Arc<State>,
+    connection: Arc<Connection>,
+) -> impl Service<
+    Request<Body>,
+    Response = Response<Body>,
+    Error = RequestError,
+    Future = impl Future<Output = Result<Response<Body>, RequestError>> + Send,
+> + Send {
+    service::service_fn(move |incoming: Request<Body>| {
+        #[cfg(feature = "expose-metrics")]
+        {
+            let uri = incoming.uri();
 
-async fn handle_request(
-    client: Client,
-    request: Request<Body>,
-) -> Result<Response<Body>, RequestError> {
-    let api_url: String = format!("/api/v{}/", API_VERSION);
-    debug!("Incoming request: {:?}", request);
+            if uri.path() == "/metrics" {
+                handle_metrics()
+            } else {
+                Box::pin(handle(state.clone(), connection.clone(), incoming))
+            }
+        }
 
-    let (parts, body) = request.into_parts();
-    let Parts {
-        method,
-        uri,
-        headers,
-        ..
-    } = parts;
-    let converted_method = convert_method(method.clone())?;
+        #[cfg(not(feature = "expose-metrics"))]
+        {
+            handle(state.clone(), connection.clone(), incoming)
+        }
+    })
+}
 
-    let trimmed_path = if uri.path().starts_with(&api_url) {
-        uri.path().replace(&api_url, "")
-    } else {
-        uri.path().to_owned()
-    };
-    let path = Path::try_from((converted_method, trimmed_path.as_ref())).context(InvalidPath)?;
+/// Accepts and serves connections without hyper's server, for reading their
+/// PROXY protocol headers and closing those that stay idle for too long.
+///
+/// With the PROXY protocol, connections without a valid header are closed.
+async fn serve_connections(listener: TcpListener, state: Arc<State>) -> IoResult<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let http = Http::new();
 
-    let bytes = (hyper::body::to_bytes(body).await.context(ChunkingRequest)?).to_vec();
+    loop {
+        // Waiting for a connection to close before accepting another leaves
+        // new ones in the listen backlog.
+        let permit = match &state.connections {
+            Some(connections) => connections.clone().acquire_owned().await.ok(),
+            None => None,
+        };
 
-    let path_and_query = match uri.path_and_query() {
-        Some(v) => v.as_str().replace(&api_url, "").into(),
-        None => {
-            debug!("No path in URI: {:?}", uri);
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Such as running out of file descriptors, which waiting may fix.
+            Err(why) => {
+                error!("Error accepting connection: {}", why);
+                tokio::time::sleep(Duration::from_secs(1)).await;
 
-            return Err(RequestError::NoPath { uri });
+                continue;
+            }
+        };
+        if let Err(why) = configure_stream(&stream, &state.config()) {
+            debug!("Error configuring connection from {}: {}", addr, why);
         }
-    };
-    let body = if bytes.is_empty() { None } else { Some(bytes) };
-    let p = path_name(&path);
-    let m = method.as_str();
-    let raw_request = TwilightRequest {
-        body,
-        form: None,
-        headers: Some(headers),
-        method: converted_method,
-        path,
-        path_str: path_and_query,
-    };
 
-    #[cfg(feature = "expose-metrics")]
-    let start = Instant::now();
+        let http = http.clone();
+        let state = state.clone();
 
-    let resp = client.raw(raw_request).await.context(RequestIssue)?;
+        tokio::spawn(async move {
+            let config = state.config();
+            let peer = if config.proxy_protocol {
+                // A connection that never sends its header would otherwise hold
+                // its task, and its slot under `MAX_CONNECTIONS`, forever.
+                let timeout = config.inbound_idle_timeout.unwrap_or(PROXY_HEADER_TIMEOUT);
+                let header =
+                    tokio::time::timeout(timeout, proxy_protocol::read_header(&mut stream));
 
-    #[cfg(feature = "expose-metrics")]
-    let end = Instant::now();
+                match header.await {
+                    Ok(Ok(peer)) => peer.unwrap_or(addr),
+                    Ok(Err(why)) => {
+                        debug!("Closing connection from {}: {}", addr, why);
 
-    trace!("Response: {:?}", resp);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("Closing connection from {}: no PROXY header", addr);
 
-    #[cfg(feature = "expose-metrics")]
-    HISTOGRAM
-        .with_label_values(&[m, p, resp.status().to_string().as_str()])
-        .observe((end - start).as_secs_f64());
+                        return;
+                    }
+                }
+            } else {
+                addr
+            };
 
-    debug!("{} {}: {}", m, p, resp.status());
+            debug!("Connection from: {} via {}", peer, addr);
+            let idle_timeout = config.inbound_idle_timeout;
+            drop(config);
+            let connection = Arc::new(Connection::new(peer, permit));
+            let service = connection_service(state, connection.clone());
+            let serving = http.serve_connection(stream, service);
+            tokio::pin!(serving);
 
-    Ok(resp)
+            let result = match idle_timeout {
+                Some(timeout) => loop {
+                    let idle = connection.idle_for();
+
+                    // Hyper finishes any response it's sending before closing.
+                    if idle >= timeout {
+                        debug!("Closing idle connection from {}", peer);
+                        serving.as_mut().graceful_shutdown();
+
+                        break serving.await;
+                    }
+
+                    // A request since may have started the idle time over, so
+                    // it's checked again rather than the connection closed.
+                    tokio::select! {
+                        result = serving.as_mut() => break result,
+                        _ = tokio::time::sleep(timeout - idle) => {}
+                    }
+                },
+                None => serving.await,
+            };
+
+            if let Err(why) = result {
+                debug!("Error serving connection from {}: {}", peer, why);
+            }
+        });
+    }
 }
 
-fn convert_method(method: http::Method) -> Result<Method, RequestError> {
-    match method {
-        http::Method::DELETE => Ok(Method::Delete),
-        http::Method::GET => Ok(Method::Get),
-        http::Method::PATCH => Ok(Method::Patch),
-        http::Method::POST => Ok(Method::Post),
-        http::Method::PUT => Ok(Method::Put),
-        other => Err(RequestError::MethodNotAllowed { method: String::from(other.as_str()) })
+/// Reads the tokens again for clients being built, so that they use a rotated
+/// token, keeping the last ones read if they can't be read now.
+fn reload_tokens(source: &TokenSource, last: &Mutex<String>) -> String {
+    let mut last = last.lock().unwrap_or_else(PoisonError::into_inner);
+
+    match source.load() {
+        Ok(tokens) if tokens != *last => {
+            info!("Building clients with rotated tokens");
+            *last = tokens;
+        }
+        Ok(_) => {}
+        Err(why) => warn!("Building clients with the previous tokens: {}", why),
     }
+
+    last.clone()
 }
 
-#[cfg(feature = "expose-metrics")]
-fn handle_metrics() -> Pin<Box<dyn Future<Output = Result<Response<Body>, RequestError>> + Send>> {
-    Box::pin(async move {
-        let mut buffer = Vec::new();
+/// Takes one of the connections allowed at once, if their number is limited,
+/// failing if they're all taken.
+fn connection_permit(state: &State) -> Result<Option<OwnedSemaphorePermit>, RequestError> {
+    let connections = match &state.connections {
+        Some(connections) => connections,
+        None => return Ok(None),
+    };
 
-        if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
-            error!("error while encoding metrics: {:?}", e);
+    match connections.clone().try_acquire_owned() {
+        Ok(permit) => Ok(Some(permit)),
+        Err(_) => {
+            #[cfg(feature = "expose-metrics")]
+            REJECTIONS.with_label_values(&["too_many_connections"]).inc();
 
-            return Ok(Response::builder()
-                .status(500)
-                .body(Body::from(format!("{:?}", e)))
-                .unwrap())
+            Err(RequestError::TooManyConnections {
+                limit: state.config().max_connections.unwrap_or_default(),
+            })
         }
+    }
+}
 
-        match String::from_utf8(buffer) {
-            Ok(s) => {
-                Ok(Response::builder()
-                    .body(Body::from(s))
-                    .unwrap())
-            }
+/// Applies the configured socket options to an accepted connection, as hyper
+/// does for the connections it accepts itself.
+fn configure_stream(stream: &tokio::net::TcpStream, config: &Config) -> IoResult<()> {
+    stream.set_nodelay(config.tcp_nodelay)?;
 
-            Err(e) => {
-                Ok(Response::builder()
-                    .status(500)
-                    .body(Body::from(format!("{:?}", e)))
-                    .unwrap())
-            }
-        }
-    })
+    if let Some(time) = config.tcp_keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+
+    Ok(())
+}
+
+/// Creates the client for the configured tokens. Several comma-separated tokens
+/// are used in turn.
+fn upstream(tokens: &str) -> Box<dyn Upstream> {
+    upstream_via(tokens, None)
+}
+
+/// Like [`upstream`], but optionally sending requests to another host, given
+/// with whether to use plain HTTP, instead of Discord.
+fn upstream_via(tokens: &str, host: Option<&(String, bool)>) -> Box<dyn Upstream> {
+    let mut clients = token::split(tokens)
+        .map(|token| {
+            let builder = Client::builder().token(token.to_owned());
+
+            match host {
+                Some((host, use_http)) => builder.proxy(host.clone(), *use_http).build(),
+                None => builder.build(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if clients.len() == 1 {
+        Box::new(clients.remove(0))
+    } else {
+        info!("Spreading requests across {} tokens", clients.len());
+
+        Box::new(RoundRobin::new(clients))
+    }
+}
+
+/// Parses a log filter, which without any directives is the one from
+/// `RUST_LOG`, or `info` without that.
+fn log_filter(directives: Option<&str>, config: &Config) -> Result<EnvFilter, ParseError> {
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives)?,
+        None => config
+            .log_filter
+            .as_deref()
+            .and_then(|directives| EnvFilter::try_new(directives).ok())
+            .map_or_else(|| EnvFilter::try_new("info"), Ok)?,
+    };
+
+    // Slow requests are logged whatever the configured level is.
+    if config.slow_request.is_some() {
+        Ok(filter.add_directive("slow_request=warn".parse()?))
+    } else {
+        Ok(filter)
+    }
+}
+
+/// Reloads the configuration each time the process gets a `SIGHUP`.
+///
+/// `file` is the variables read from `CONFIG_FILE` at startup.
+#[cfg(unix)]
+fn reload_on_hangup(
+    state: Arc<State>,
+    mut file: HashMap<String, String>,
+) -> IoResult<tokio::task::JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+
+    Ok(tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Reloading the configuration");
+
+            let path = env::var_os("CONFIG_FILE");
+
+            if let Err(why) = reload(&state, path.as_ref().map(AsRef::as_ref), &mut file) {
+                error!("Error reloading the configuration: {}", why);
+            }
+        }
+    }))
+}
+
+/// Rereads the config file at `path` and the environment, applying the
+/// settings that can change while the proxy runs and logging those that need a
+/// restart.
+///
+/// `file` is the variables the file had when it was last read, which are
+/// replaced with those it has now.
+#[cfg(unix)]
+fn reload(
+    state: &State,
+    path: Option<&std::path::Path>,
+    file: &mut HashMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let read = match path {
+        Some(path) => config::load_file(path)?,
+        None => HashMap::new(),
+    };
+
+    let running = state.config();
+    let wanted = Config::from_env_with(&read)?;
+    let applied = wanted.clone().keep_startup_settings(&running);
+    let filter = log_filter(None, &applied)?;
+
+    for name in config::RESTART_VARIABLES {
+        if config::lookup(file, name) != config::lookup(&read, name) {
+            warn!("{} changed, restart required to apply it", name);
+        }
+    }
+
+    *file = read;
+
+    let (running_json, applied_json) = (running.redacted(), applied.redacted());
+
+    if let serde_json::Value::Object(wanted_json) = wanted.redacted() {
+        for (name, value) in wanted_json {
+            if running_json.get(&name) == Some(&value) {
+                continue;
+            }
+
+            if applied_json.get(&name) == Some(&value) {
+                info!("{} changed to {}", name, value);
+            } else {
+                warn!("{} changed, restart required to apply it", name);
+            }
+        }
+    }
+
+    // Left alone unless the configured filter changed, so one set through the
+    // admin endpoints survives reloads as the maintenance switch does.
+    if filter.to_string() != log_filter(None, &running)?.to_string() {
+        info!("Log filter changed to {}", filter);
+
+        state.log_filter.reload(filter)?;
+    }
+
+    // Left alone unless the variable changed, so a switch made through the
+    // admin endpoints survives reloads.
+    if applied.maintenance_mode != running.maintenance_mode {
+        state
+            .maintenance
+            .store(applied.maintenance_mode, Ordering::Relaxed);
+    }
+
+    *state.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(applied);
+
+    Ok(())
+}
+
+/// Takes the listening socket systemd passed with socket activation, if it did.
+/// Only the first is used when there are several.
+#[cfg(unix)]
+fn inherited_listener() -> IoResult<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    /// The first descriptor passed, after stdin, stdout and stderr.
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or_default();
+
+    // Children mustn't think the sockets were passed to them.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if pid != Some(process::id()) || count == 0 {
+        return Ok(None);
+    }
+
+    if count > 1 {
+        warn!("systemd passed {} sockets, only the first is used", count);
+    }
+
+    // Safety: systemd passes the sockets as descriptors from 3 on, and nothing
+    // else has taken ownership of them.
+    let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn inherited_listener() -> IoResult<Option<TcpListener>> {
+    Ok(None)
+}
+
+/// Parses the address to listen on from `HOST`, which may be a bracketed IPv6
+/// address and may carry a scope, such as `fe80::1%eth0`.
+fn listen_address(host: &str, port: u16) -> Result<SocketAddr, Box<dyn Error>> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let mut parts = unbracketed.splitn(2, '%');
+    let ip = IpAddr::from_str(parts.next().unwrap_or_default())?;
+
+    match (ip, parts.next()) {
+        (IpAddr::V6(ip), Some(scope)) => {
+            Ok(SocketAddrV6::new(ip, port, 0, scope_id(scope)?).into())
+        }
+        (IpAddr::V4(_), Some(_)) => Err(format!("IPv4 host {} can't have a scope", host).into()),
+        (ip, None) => Ok(SocketAddr::from((ip, port))),
+    }
+}
+
+/// The index of the network interface an IPv6 scope names, which may also be
+/// given as the index itself.
+fn scope_id(scope: &str) -> Result<u32, Box<dyn Error>> {
+    if let Ok(index) = scope.parse() {
+        return Ok(index);
+    }
+
+    // Linux lists the index of each interface under /sys.
+    match fs::read_to_string(format!("/sys/class/net/{}/ifindex", scope)) {
+        Ok(index) if !scope.contains('/') => Ok(index.trim().parse()?),
+        _ => Err(format!("IPv6 scope {:?} isn't a known network interface", scope).into()),
+    }
+}
+
+/// Creates the listening socket with the configured socket options.
+fn bind(address: SocketAddr, config: &Config) -> IoResult<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(config.reuse_address)?;
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(config.reuse_port)?;
+
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Whether a route's responses can be so large that they're passed on as they
+/// arrive, rather than buffered to be shared with identical requests or
+/// compressed.
+fn streams_response(path: &Path) -> bool {
+    matches!(
+        path,
+        Path::GuildsIdAuditLogs(..) | Path::GuildsIdBans(..) | Path::GuildsIdMembers(..)
+    )
+}
+
+/// Name of every route [`path_name`] doesn't know.
+const UNKNOWN_PATH: &str = "Unknown path!";
+
+/// Priority of the routes not given one in `ROUTE_PRIORITIES`.
+const DEFAULT_ROUTE_PRIORITY: u32 = 1;
+
+/// Methods that requests can be forwarded with, as [`convert_method`] accepts.
+const SUPPORTED_METHODS: &[&str] = &["DELETE", "GET", "PATCH", "POST", "PUT"];
+
+fn path_name(path: &Path) -> &'static str {
+    match path {
+        Path::ChannelsId(..) => "Channel",
+        Path::ChannelsIdInvites(..) => "Channel invite",
+        Path::ChannelsIdMessages(..) => "Channel message",
+        Path::ChannelsIdMessagesBulkDelete(..) => "Bulk delete message",
+        Path::ChannelsIdMessagesId(..) => "Channel message",
+        Path::ChannelsIdMessagesIdReactions(..) => "Message reaction",
+        Path::ChannelsIdMessagesIdReactionsUserIdType(..) => "Message reaction for user",
+        Path::ChannelsIdPermissionsOverwriteId(..) => "Channel permission override",
+        Path::ChannelsIdPins(..) => "Channel pins",
+        Path::ChannelsIdPinsMessageId(..) => "Specific channel pin",
+        Path::ChannelsIdTyping(..) => "Typing indicator",
+        Path::ChannelsIdWebhooks(..) => "Webhook",
+        Path::Gateway => "Gateway",
+        Path::GatewayBot => "Gateway bot info",
+        Path::Guilds => "Guilds",
+        Path::GuildsId(..) => "Guild",
+        Path::GuildsIdBans(..) => "Guild bans",
+        Path::GuildsIdAuditLogs(..) => "Guild audit logs",
+        Path::GuildsIdBansUserId(..) => "Guild ban for user",
+        Path::GuildsIdChannels(..) => "Guild channel",
+        Path::GuildsIdWidget(..) => "Guild widget",
+        Path::GuildsIdEmojis(..) => "Guild emoji",
+        Path::GuildsIdEmojisId(..) => "Specific guild emoji",
+        Path::GuildsIdIntegrations(..) => "Guild integrations",
+        Path::GuildsIdIntegrationsId(..) => "Specific guild integration",
+        Path::GuildsIdIntegrationsIdSync(..) => "Sync guild integration",
+        Path::GuildsIdInvites(..) => "Guild invites",
+        Path::GuildsIdMembers(..) => "Guild members",
+        Path::GuildsIdMembersId(..) => "Specific guild member",
+        Path::GuildsIdMembersIdRolesId(..) => "Guild member role",
+        Path::GuildsIdMembersMeNick(..) => "Modify own nickname",
+        Path::GuildsIdPreview(..) => "Guild preview",
+        Path::GuildsIdPrune(..) => "Guild prune",
+        Path::GuildsIdRegions(..) => "Guild region",
+        Path::GuildsIdRoles(..) => "Guild roles",
+        Path::GuildsIdRolesId(..) => "Specific guild role",
+        Path::GuildsIdVanityUrl(..) => "Guild vanity invite",
+        Path::GuildsIdWebhooks(..) => "Guild webhooks",
+        Path::InvitesCode => "Invite info",
+        Path::UsersId => "User info",
+        Path::UsersIdConnections => "User connections",
+        Path::UsersIdChannels => "User channels",
+        Path::UsersIdGuilds => "User in guild",
+        Path::UsersIdGuildsId => "Guild from user",
+        Path::VoiceRegions => "Voice region list",
+        Path::WebhooksId(..) => "Webhook",
+        Path::OauthApplicationsMe => "Current application info",
+        _ => UNKNOWN_PATH,
+    }
+}
+
+/// A route, as parsed from a request's path.
+struct Route {
+    /// Path the proxy's ratelimiter buckets the request under.
+    path: Path,
+    /// Name of the route in the metrics, which several routes may share.
+    name: &'static str,
+    /// Name of the route alone, by which it's configured.
+    key: Cow<'static, str>,
+}
+
+/// Parses a path, falling back on [`unlisted_route`] for routes twilight
+/// doesn't know. Routes twilight knows are keyed by the name of their [`Path`]
+/// variant.
+fn parse_path(method: Method, path: &str) -> Result<Route, PathParseError> {
+    match Path::try_from((method, path)) {
+        Ok(path) => {
+            let variant = format!("{:?}", path);
+            let key = variant.split('(').next().unwrap_or_default().to_owned();
+
+            Ok(Route {
+                name: path_name(&path),
+                path,
+                key: Cow::Owned(key),
+            })
+        }
+        Err(PathParseError::NoMatch) => unlisted_route(path).ok_or(PathParseError::NoMatch),
+        Err(why) => Err(why),
+    }
+}
+
+/// Routes added to Discord's API since this version of twilight, each with its
+/// own name but sharing the bucket of the [`Path`] with the same major
+/// parameter in the proxy's ratelimiter. The path is still forwarded as given.
+fn unlisted_route(path: &str) -> Option<Route> {
+    let parts = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    let id = |segment: &str| segment.parse().ok();
+
+    let (path, key, name) = match parts.as_slice() {
+        ["channels", channel, "threads", ..] => (
+            Path::ChannelsId(id(channel)?),
+            "ChannelsIdThreads",
+            "Channel threads",
+        ),
+        ["channels", channel, "messages", _, "threads"] => (
+            Path::ChannelsId(id(channel)?),
+            "ChannelsIdMessagesIdThreads",
+            "Thread from message",
+        ),
+        ["channels", channel, "thread-members", ..] => (
+            Path::ChannelsId(id(channel)?),
+            "ChannelsIdThreadMembers",
+            "Thread member",
+        ),
+        ["guilds", guild, "threads", "active"] => (
+            Path::GuildsId(id(guild)?),
+            "GuildsIdThreadsActive",
+            "Active guild threads",
+        ),
+        ["applications", _, "commands", ..] => (
+            Path::OauthApplicationsMe,
+            "ApplicationsIdCommands",
+            "Global application commands",
+        ),
+        ["applications", _, "guilds", guild, "commands", ..] => (
+            Path::GuildsId(id(guild)?),
+            "ApplicationsIdGuildsIdCommands",
+            "Guild application commands",
+        ),
+        ["interactions", interaction, _, "callback"] => (
+            Path::WebhooksId(id(interaction)?),
+            "InteractionsIdTokenCallback",
+            "Interaction callback",
+        ),
+        ["webhooks", webhook, _, "messages", _] => (
+            Path::WebhooksIdTokenMessageId(id(webhook)?),
+            "WebhooksIdTokenMessagesId",
+            "Webhook message",
+        ),
+        _ => return None,
+    };
+
+    Some(Route {
+        path,
+        name,
+        key: Cow::Borrowed(key),
+    })
+}
+
+/// Handles a request, turning any error into a response for the client.
+async fn handle(
+    state: Arc<State>,
+    connection: Arc<Connection>,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let _in_flight = state.stats.start();
+    let _handling = connection.start_request();
+
+    // Answered whatever else the proxy is doing, even in maintenance mode.
+    if request.uri().path() == "/health" {
+        return Ok(Response::new(Body::from("ok")));
+    }
+
+    let is_admin =
+        state.config().admin_secret.is_some() && request.uri().path().starts_with("/admin/");
+    let is_batch = state.config().enable_batch
+        && request.method() == http::Method::POST
+        && request.uri().path() == "/batch";
+
+    // The token is only known once the request has been read.
+    let span = info_span!("request", environment = field::Empty, token = field::Empty);
+
+    if let Some(environment) = &state.config().environment {
+        span.record("environment", &environment.as_str());
+    }
+
+    if request.uri().path() == "/ready" {
+        return Ok(ready_response(&state).await);
+    }
+
+    let path = request.uri().path().to_owned();
+
+    let result = if is_admin {
+        admin::handle(&state, request).instrument(span).await
+    } else if is_batch {
+        batch::handle(&state, &connection, request)
+            .instrument(span)
+            .await
+    } else {
+        handle_request(&state, &connection, request)
+            .instrument(span)
+            .await
+    };
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(why) => error_response(&state.config(), &path, why),
+    };
+
+    // Set before injected headers, which may replace it.
+    if let Some(server) = &state.config().server_header {
+        response.headers_mut().insert(SERVER, server.clone());
+    }
+
+    inject_headers(response.headers_mut(), &state.config());
+
+    Ok(response)
+}
+
+/// The response to a request for `path` that failed, in the configured format.
+fn error_response(config: &Config, path: &str, why: RequestError) -> Response<Body> {
+    debug!("Error handling request: {:?}", why);
+
+    #[cfg(feature = "expose-metrics")]
+    if let Some(reason) = why.rejection() {
+        REJECTIONS.with_label_values(&[reason]).inc();
+    }
+
+    match config.error_format {
+        ErrorFormat::ProblemJson => why.into_problem_response(path),
+        ErrorFormat::Text => why.into_response(),
+    }
+}
+
+async fn handle_request(
+    state: &State,
+    connection: &Connection,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let received = Instant::now();
+    let config = &state.config();
+    let api_url: String = format!("/api/v{}/", API_VERSION);
+    debug!("Incoming request from {}: {:?}", connection.peer, request);
+
+    // `OPTIONS *` asks about the server itself rather than any route.
+    if request.method() == http::Method::OPTIONS && request.uri() == "*" {
+        return Ok(server_options_response(config));
+    }
+
+    if let Some(limit) = config.max_uri_length {
+        let length = request
+            .uri()
+            .path_and_query()
+            .map_or(0, |pq| pq.as_str().len());
+
+        if length > limit {
+            return Err(RequestError::UriTooLong { length, limit });
+        }
+    }
+
+    if state.maintenance.load(Ordering::Relaxed) {
+        return Ok(maintenance_response(config));
+    }
+
+    if let Some(host) = &config.cdn_host {
+        let cdn_path = request
+            .uri()
+            .path_and_query()
+            .and_then(|pq| pq.as_str().strip_prefix("/cdn/"))
+            .map(|rest| format!("/{}", rest));
+
+        if let Some(cdn_path) = cdn_path {
+            return handle_cdn(state, host, &cdn_path, request).await;
+        }
+    }
+
+    let (parts, body) = request.into_parts();
+    let Parts {
+        method,
+        uri,
+        version,
+        mut headers,
+        ..
+    } = parts;
+    let method = if config.allow_method_override {
+        override_method(method, &mut headers)?
+    } else {
+        method
+    };
+    if let Some(allowed) = &config.allowed_methods {
+        if !allowed.contains(&method) {
+            return Err(RequestError::MethodNotAllowed {
+                method: String::from(method.as_str()),
+            });
+        }
+    }
+
+    let converted_method = convert_method(method.clone())?;
+    let deadline = take_deadline(&mut headers, config.max_request_deadline)?
+        .map(|deadline| received + deadline);
+
+    let trimmed_path = trim_api_prefix(uri.path(), &api_url);
+    let Route {
+        path,
+        name: route,
+        key,
+    } = parse_path(converted_method, trimmed_path).context(InvalidPath {
+        method: method.as_str(),
+        path: trimmed_path,
+    })?;
+
+    if config.reject_unknown_routes && route == UNKNOWN_PATH {
+        return Err(RequestError::UnknownRoute {
+            path: trimmed_path.to_owned(),
+        });
+    }
+
+    // Past the threshold, only the highest priority routes are still served.
+    // With no priorities configured every route has the same one, so nothing is
+    // shed.
+    if let Some(threshold) = config.shed_threshold {
+        let priority = config
+            .route_priorities
+            .get(&*key)
+            .copied()
+            .unwrap_or(DEFAULT_ROUTE_PRIORITY);
+        let highest = config
+            .route_priorities
+            .values()
+            .copied()
+            .fold(DEFAULT_ROUTE_PRIORITY, u32::max);
+
+        if state.stats.in_flight() > threshold && priority < highest {
+            return Err(RequestError::Shed { route });
+        }
+    }
+
+    // Expensive routes may be limited to so many requests at once, whichever
+    // bots make them.
+    let _route_permit = match state.route_limits.get(&*key) {
+        Some(permits) => match permits.try_acquire() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                return Err(RequestError::RouteConcurrency {
+                    limit: config.route_concurrency[&*key],
+                    route,
+                })
+            }
+        },
+        None => None,
+    };
+
+    // Hyper only tells a client waiting on `Expect: 100-continue` to send its
+    // body once the body is read, so anything rejected before then is never
+    // uploaded.
+    if let Some(expect) = headers.remove(EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Err(RequestError::ExpectationFailed {
+                expectation: String::from_utf8_lossy(expect.as_bytes()).into_owned(),
+            });
+        }
+    }
+
+    if let Some(limit) = config.max_body_size {
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+
+        if length.is_some_and(|length| length > limit) {
+            return Err(RequestError::PayloadTooLarge { limit });
+        }
+    }
+
+    #[cfg(feature = "expose-metrics")]
+    let body_start = Instant::now();
+
+    let reading = read_body(body, config.max_body_size);
+    let bytes = match config.read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, reading)
+            .await
+            .map_err(|_| RequestError::RequestTimeout {
+                timeout_ms: timeout.as_millis() as u64,
+            })??,
+        None => reading.await?,
+    };
+
+    #[cfg(feature = "expose-metrics")]
+    BODY_HISTOGRAM
+        .with_label_values(&[method.as_str(), route])
+        .observe(body_start.elapsed().as_secs_f64());
+
+    // The body has been read in full, so it's forwarded with a length computed
+    // from what was read rather than however the client framed it, which may
+    // have been chunked.
+    headers.remove(TRANSFER_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+
+    let mut path_and_query = match uri.path_and_query() {
+        Some(v) => trim_api_prefix(v.as_str(), &api_url).to_owned(),
+        None => {
+            debug!("No path in URI: {:?}", uri);
+
+            return Err(RequestError::NoPath { uri });
+        }
+    };
+
+    if let Some(content_type) = &config.default_content_type {
+        if !bytes.is_empty() && !headers.contains_key(CONTENT_TYPE) {
+            headers.insert(CONTENT_TYPE, content_type.clone());
+        }
+    }
+
+    if config.strict_content_type
+        && !bytes.is_empty()
+        && matches!(converted_method, Method::Patch | Method::Post | Method::Put)
+    {
+        check_content_type(&headers)?;
+    }
+
+    if (config.log_json_bodies || config.reject_invalid_json)
+        && !bytes.is_empty()
+        && is_json(&headers)
+    {
+        match serde_json::from_slice::<serde_json::Value>(&bytes) {
+            Ok(mut value) if config.log_json_bodies => {
+                json::redact(&mut value);
+
+                if let Ok(pretty) = serde_json::to_string_pretty(&value) {
+                    debug!("Request body: {}", pretty);
+                }
+            }
+            Ok(_) => {}
+            Err(source) => {
+                warn!("Request body isn't valid JSON: {}", source);
+
+                if config.reject_invalid_json {
+                    return Err(RequestError::InvalidJson { source });
+                }
+            }
+        }
+    }
+
+    // Behind another auth system, `Authorization` is that system's and isn't
+    // for Discord, and the token comes in a header of its own.
+    if let Some(name) = &config.token_header {
+        headers.remove(AUTHORIZATION);
+
+        if let Some(value) = headers.remove(name) {
+            headers.insert(AUTHORIZATION, value);
+        }
+    }
+
+    // Requests without any `Authorization` header use the global token, but one
+    // that's there and unusable is a client bug worth pointing out.
+    if let Some(value) = headers.get(AUTHORIZATION) {
+        let usable = value
+            .to_str()
+            .is_ok_and(|value| !value.trim().is_empty());
+
+        if !usable {
+            return Err(RequestError::InvalidAuthorization);
+        }
+    }
+
+    if config.allow_query_token && !headers.contains_key(AUTHORIZATION) {
+        if let Some(token) = take_query_token(&mut path_and_query) {
+            let value = HeaderValue::from_str(&format!("Bot {}", token))
+                .map_err(|_| RequestError::InvalidQueryToken)?;
+            headers.insert(AUTHORIZATION, value);
+        }
+    }
+
+    // A bot token whose first segment doesn't decode to an id, such as one
+    // that's empty, is malformed however Discord would answer it.
+    if config.reject_invalid_bot_id {
+        if let Some(value) = headers.get(AUTHORIZATION) {
+            if value.as_bytes().starts_with(b"Bot ") && bot_id(state, value).is_none() {
+                return Err(RequestError::InvalidBotId);
+            }
+        }
+    }
+
+    if let Some(limit) = config.max_tokens_per_connection {
+        if let Some(value) = headers.get(AUTHORIZATION) {
+            let mut tokens = connection
+                .tokens
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let hash = token::hash(value.as_bytes());
+
+            // Tokens past the limit aren't remembered, so a connection scanning
+            // tokens can't grow the set.
+            if !tokens.contains(&hash) {
+                if tokens.len() >= limit {
+                    return Err(RequestError::TooManyTokens { limit });
+                }
+
+                tokens.insert(hash);
+            }
+        }
+    }
+
+    if let Some((name, value)) = &config.tracking_header {
+        headers.insert(name.clone(), value.clone());
+    }
+
+    let act_as = headers.remove(ACT_AS_TOKEN);
+
+    // The hook approves acting as another bot for the caller's own, which has
+    // to be given.
+    if act_as.is_some() && !headers.contains_key(AUTHORIZATION) {
+        return Err(RequestError::ActAsWithoutAuthorization);
+    }
+
+    // The caller's key is only meant for the proxy, not for Discord.
+    let key = if state.auth_hook.is_some() || state.quotas.is_some() {
+        headers.remove(&config.auth_hook_key_header)
+    } else {
+        None
+    };
+
+    if let Some(hook) = &state.auth_hook {
+        let primary = headers.get(AUTHORIZATION);
+        let primary_bot_id = primary.and_then(|value| bot_id(state, value));
+        let attempt = Attempt {
+            key: key.as_ref().and_then(|value| value.to_str().ok()),
+            bot_id: match &act_as {
+                Some(token) => bot_id(state, token),
+                None => primary_bot_id,
+            },
+            act_as: act_as.is_some(),
+            has_primary: primary.is_some(),
+            primary_bot_id,
+            route,
+            method: method.as_str(),
+        };
+
+        hook.check(&state.cdn, &attempt).await?;
+    }
+
+    // Only the hook can say whether a caller may act as another bot, which it
+    // has by now.
+    if let Some(token) = act_as {
+        if state.auth_hook.is_none() {
+            return Err(RequestError::ActAsWithoutHook);
+        }
+
+        headers.insert(AUTHORIZATION, token);
+    }
+
+    // Callers are told apart by their key if they have one, and otherwise by
+    // the bot they're making requests as.
+    let remaining_quota = match &state.quotas {
+        Some(quotas) => {
+            let caller = match key.as_ref().and_then(|value| value.to_str().ok()) {
+                Some(key) => Some(key.to_owned()),
+                None => headers
+                    .get(AUTHORIZATION)
+                    .map_or(state.default_bot_id, |value| bot_id(state, value))
+                    .map(|id| id.to_string()),
+            };
+
+            match caller {
+                Some(caller) => quotas
+                    .take(&caller)
+                    .map_err(|retry_after| RequestError::QuotaExceeded { retry_after })?,
+                None => None,
+            }
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "expose-metrics")]
+    if config.bot_metrics {
+        let bot_id = match headers.get(AUTHORIZATION) {
+            Some(value) => bot_id(state, value),
+            None => state.default_bot_id,
+        };
+        let label = bot_id.map_or_else(|| "unknown".to_owned(), |id| id.to_string());
+
+        BOT_REQUESTS.with_label_values(&[&label]).inc();
+    }
+
+    let canary = match &state.canary {
+        Some(canary) => headers
+            .remove(&config.canary_header)
+            .filter(|value| matches!(value.as_bytes(), b"1" | b"true"))
+            .map(|_| canary),
+        None => None,
+    };
+
+    // Requests without their own token share the global token's entries.
+    let token_hash = headers
+        .get(AUTHORIZATION)
+        .map_or(0, |value| token::hash(value.as_bytes()));
+
+    // The hash tells bots apart in the logs without revealing their tokens, and
+    // is the same for every request with a token.
+    if headers.contains_key(AUTHORIZATION) {
+        Span::current().record("token", &format!("{:016x}", token_hash).as_str());
+    }
+
+    if let Some(usage) = &state.token_usage {
+        usage.record(token_hash, || match headers.get(AUTHORIZATION) {
+            Some(value) => bot_id(state, value),
+            None => state.default_bot_id,
+        });
+    }
+    let idempotency_key = match &state.idempotency {
+        Some(_) => headers.remove(IDEMPOTENCY_KEY),
+        None => None,
+    };
+    // Replays of idempotent requests are never revalidated, since they must not
+    // reach Discord twice.
+    let cache = match (&state.idempotency, idempotency_key, &path, converted_method) {
+        (Some(cache), Some(key), ..) => Some((
+            cache,
+            request_key((token_hash, key.as_bytes(), method.as_str(), &path_and_query)),
+            false,
+        )),
+        (_, _, Path::GatewayBot, Method::Get) if state.gateway_bot.is_some() => state
+            .gateway_bot
+            .as_ref()
+            .map(|cache| (cache, token_hash, wants_revalidation(&headers))),
+        (_, _, path, Method::Get) if !streams_response(path) => {
+            state.single_flight.as_ref().map(|cache| {
+                let bot_id = headers
+                    .get(AUTHORIZATION)
+                    .map_or(state.default_bot_id, |value| bot_id(state, value));
+                let key = request_key((bot_id, token_hash, &path_and_query));
+
+                (cache, key, false)
+            })
+        }
+        _ => None,
+    };
+    // Canaried responses mustn't be shared with requests that weren't.
+    let cache = cache.filter(|_| canary.is_none());
+
+    let accepts_gzip =
+        config.response_gzip.is_some() && !streams_response(&path) && gzip::accepted(&headers);
+
+    let size = bytes.len();
+    let user_agent = config
+        .access_log
+        .and_then(|_| headers.get(USER_AGENT).cloned());
+    let body = if bytes.is_empty() { None } else { Some(bytes) };
+    let p = route;
+    let m = method.as_str();
+
+    // Only requests that are safe to send twice are shadowed.
+    let shadow = match (&state.shadow, converted_method) {
+        (Some(shadow), Method::Get) => Some(spawn_shadow(
+            shadow.clone(),
+            TwilightRequest {
+                body: None,
+                form: None,
+                headers: Some(headers.clone()),
+                method: Method::Get,
+                path: path.clone(),
+                path_str: path_and_query.clone().into(),
+            },
+            p,
+        )),
+        _ => None,
+    };
+
+    let _queued = match &state.buckets {
+        Some(buckets) => Some(
+            buckets
+                .enter(&path, p)
+                .ok_or(RequestError::BucketQueueFull { route: p })?,
+        ),
+        None => None,
+    };
+
+    // Only Discord's own ratelimits are of interest, not the canary's.
+    let observed = match (&state.ratelimits, canary) {
+        (Some(ratelimits), None) => Some((
+            ratelimits,
+            headers
+                .get(AUTHORIZATION)
+                .map_or(state.default_bot_id, |value| bot_id(state, value)),
+            path.clone(),
+        )),
+        _ => None,
+    };
+
+    let raw_request = TwilightRequest {
+        body,
+        form: None,
+        headers: Some(headers),
+        method: converted_method,
+        path,
+        path_str: path_and_query.into(),
+    };
+
+    let start = Instant::now();
+
+    let upstream = canary.unwrap_or(&state.client);
+    // Left unset when the response comes from a cache or another request.
+    let fetched = AtomicBool::new(false);
+    let fetch = || async {
+        fetched.store(true, Ordering::Relaxed);
+        let (mut retries, mut wait_left) = config.handle_429.unwrap_or_default();
+        let mut raw_request = raw_request;
+
+        loop {
+            // Only requests that reach Discord count, not those answered from a
+            // cache or by another request.
+            if let Some(limit) = &state.global_limit {
+                let taken = limit.take();
+
+                #[cfg(feature = "expose-metrics")]
+                GLOBAL_THROTTLED.set(i64::from(taken.is_err()));
+
+                taken.map_err(|retry_after| RequestError::GlobalLimitExceeded { retry_after })?;
+            }
+
+            // Copied before it's sent, in case it has to be sent again. Forms
+            // can't be copied, but the proxy never sends any.
+            let copy = Some(&raw_request)
+                .filter(|request| retries > 0 && request.form.is_none())
+                .map(|request| TwilightRequest {
+                    body: request.body.clone(),
+                    form: None,
+                    headers: request.headers.clone(),
+                    method: request.method,
+                    path: request.path.clone(),
+                    path_str: request.path_str.clone(),
+                });
+
+            let resp = upstream.raw(raw_request).await.context(RequestIssue)?;
+
+            if let Some((ratelimits, bot_id, path)) = &observed {
+                ratelimits.record(*bot_id, path, p, resp.headers());
+            }
+
+            #[cfg(feature = "expose-metrics")]
+            UPSTREAM_RESPONSES
+                .with_label_values(&[status_class(resp.status())])
+                .inc();
+
+            let delay = match (retry_429_delay(&resp, wait_left), copy) {
+                (Some(delay), Some(copy)) => {
+                    raw_request = copy;
+
+                    delay
+                }
+                _ => break Ok(resp),
+            };
+
+            debug!("Retrying a 429 for {} {} in {}ms", m, p, delay.as_millis());
+
+            retries -= 1;
+            wait_left = wait_left.saturating_sub(delay);
+            tokio::time::sleep(delay).await;
+        }
+    };
+    let fetching = async {
+        match cache {
+            Some((cache, key, revalidate)) => cache.get(key, revalidate, fetch).await,
+            None => fetch().await,
+        }
+    };
+    // The deadline covers waiting for the ratelimiter as well as Discord.
+    let mut resp = match deadline {
+        Some(deadline) => {
+            let deadline_ms = deadline.duration_since(received).as_millis() as u64;
+
+            if Instant::now() >= deadline {
+                return Err(RequestError::DeadlineExceeded { deadline_ms });
+            }
+
+            tokio::time::timeout_at(deadline.into(), fetching)
+                .await
+                .map_err(|_| RequestError::DeadlineExceeded { deadline_ms })??
+        }
+        None => fetching.await?,
+    };
+
+    let elapsed = start.elapsed();
+
+    trace!("Response: {:?}", resp);
+
+    // Logged before the allowlist can remove the headers.
+    if config.log_ratelimits {
+        log_ratelimits(m, p, &resp);
+    }
+
+    if let Some(allowed) = &config.response_header_allowlist {
+        retain_headers(resp.headers_mut(), allowed);
+    }
+
+    strip_headers(resp.headers_mut(), &config.strip_response_headers);
+
+    if config.reset_after_ms_header {
+        add_reset_after_ms(resp.headers_mut());
+    }
+
+    if config.upstream_duration_header && fetched.load(Ordering::Relaxed) {
+        resp.headers_mut().insert(
+            "x-upstream-duration-ms",
+            HeaderValue::from(elapsed.as_millis() as u64),
+        );
+    }
+
+    if let Some(remaining) = remaining_quota {
+        resp.headers_mut()
+            .insert("x-quota-remaining", HeaderValue::from(remaining));
+    }
+
+    if config.retry_after_jitter_ms > 0 && resp.status() == StatusCode::TOO_MANY_REQUESTS {
+        add_retry_after_jitter(resp.headers_mut(), config.retry_after_jitter_ms);
+    }
+
+    // Bodies are only parsed for the routes that have fields to remove.
+    if let Some(paths) = config.response_redactions.get(p) {
+        resp = json::remove_fields(resp, paths).await?;
+    }
+
+    if let (Some(min_size), true) = (config.response_gzip, accepts_gzip) {
+        resp = gzip::compress(resp, min_size).await?;
+    }
+
+    if let Some(shadow) = shadow {
+        // The shadow gave up if it failed before it needed the status.
+        let _ = shadow.send(resp.status());
+    }
+
+    #[cfg(feature = "expose-metrics")]
+    HISTOGRAM
+        .with_label_values(&[m, p, resp.status().to_string().as_str()])
+        .observe(elapsed.as_secs_f64());
+
+    if let Some(threshold) = config.slow_request {
+        if elapsed > threshold {
+            // Logged whether or not the request span is, so it carries the
+            // token's hash itself, as the span has it.
+            let token = match token_hash {
+                0 => String::from("global"),
+                hash => format!("{:016x}", hash),
+            };
+
+            warn!(
+                target: "slow_request",
+                token = token.as_str(),
+                "{} {} took {}ms: {}",
+                m,
+                p,
+                elapsed.as_millis(),
+                resp.status()
+            );
+        }
+    }
+
+    if let Some(recent) = &state.recent {
+        recent.record(m, p, resp.status().as_u16(), size);
+    }
+
+    if let Some(format) = config.access_log {
+        let entry = access_log::Entry {
+            duration: received.elapsed(),
+            method: m,
+            peer: connection.peer.ip(),
+            route: p,
+            size: resp
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
+            status: resp.status(),
+            token: Some(token_hash).filter(|hash| *hash != 0),
+            user_agent: user_agent.as_ref().and_then(|value| value.to_str().ok()),
+            version,
+        };
+
+        access_log::log(format, &entry);
+    }
+
+    debug!("{} {}: {}", m, p, resp.status());
+
+    Ok(resp)
+}
+
+/// The class of a status for alerting on, which sets ratelimited responses
+/// apart from other client errors.
+#[cfg(feature = "expose-metrics")]
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        429 => "429",
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// Hashes what identifies a request to one of the response caches.
+fn request_key(parts: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// How long a `/gateway/bot` response is accurate for, since its session start
+/// limit is replenished after `reset_after` milliseconds.
+fn session_start_reset(response: &CachedResponse) -> Option<Duration> {
+    let value = serde_json::from_slice::<serde_json::Value>(response.body()).ok()?;
+
+    value
+        .get("session_start_limit")?
+        .get("reset_after")?
+        .as_u64()
+        .map(Duration::from_millis)
+}
+
+/// Sends a copy of a request to the shadow upstream in the background, logging
+/// how its status compares to the one sent on the returned channel.
+fn spawn_shadow(
+    shadow: Arc<dyn Upstream>,
+    request: TwilightRequest,
+    route: &'static str,
+) -> oneshot::Sender<StatusCode> {
+    let (sender, receiver) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let shadowed = shadow.raw(request).await;
+        let primary = match receiver.await {
+            Ok(status) => status,
+            // The request failed before Discord answered, so there's nothing to
+            // compare against.
+            Err(_) => return,
+        };
+
+        let outcome = match shadowed {
+            Ok(response) if response.status() == primary => "match",
+            Ok(response) => {
+                warn!(
+                    "Shadow of {} answered {}, Discord {}",
+                    route,
+                    response.status(),
+                    primary
+                );
+
+                "mismatch"
+            }
+            Err(why) => {
+                warn!("Shadow of {} failed: {}", route, why);
+
+                "error"
+            }
+        };
+
+        #[cfg(feature = "expose-metrics")]
+        SHADOW_RESULTS.with_label_values(&[outcome]).inc();
+
+        #[cfg(not(feature = "expose-metrics"))]
+        let _ = outcome;
+    });
+
+    sender
+}
+
+/// The bot a request's `Authorization` belongs to, from the cache if there is
+/// one.
+fn bot_id(state: &State, authorization: &HeaderValue) -> Option<u64> {
+    let authorization = authorization.to_str().ok()?;
+
+    match &state.bot_ids {
+        Some(bot_ids) => bot_ids.get(authorization),
+        None => token::derive_bot_id(authorization).ok(),
+    }
+}
+
+/// Removes the API version prefix from the start of a path. Anything that merely
+/// looks like the prefix further along, such as in the query, is left alone.
+fn trim_api_prefix<'a>(path: &'a str, api_url: &str) -> &'a str {
+    path.strip_prefix(api_url).unwrap_or(path)
+}
+
+/// Reads a request body in full, failing once it's longer than `limit`, which
+/// chunked bodies don't declare up front.
+///
+/// The chunks are copied straight into the buffer twilight takes, sized up
+/// front from the body's length when it's known.
+async fn read_body(mut body: Body, limit: Option<usize>) -> Result<Vec<u8>, RequestError> {
+    // A client can claim any length, so only so much is reserved on its word.
+    let declared = usize::try_from(body.size_hint().lower()).unwrap_or(usize::MAX);
+    let mut bytes = Vec::with_capacity(declared.min(limit.unwrap_or(MAX_BODY_RESERVATION)));
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.context(ChunkingRequest)?;
+
+        if let Some(limit) = limit {
+            if bytes.len() + chunk.len() > limit {
+                return Err(RequestError::PayloadTooLarge { limit });
+            }
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+/// Removes the `X-Request-Deadline-Ms` header, returning how long the client is
+/// willing to wait, up to `max`.
+fn take_deadline(headers: &mut HeaderMap, max: Duration) -> Result<Option<Duration>, RequestError> {
+    let value = match headers.remove(REQUEST_DEADLINE) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let millis = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|millis| *millis > 0)
+        .ok_or_else(|| RequestError::InvalidDeadline {
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        })?;
+
+    Ok(Some(Duration::from_millis(millis).min(max)))
+}
+
+/// Removes every `token` parameter from a path's query so it isn't sent on to
+/// Discord, returning the first one's value.
+fn take_query_token(path_and_query: &mut String) -> Option<String> {
+    let query_start = path_and_query.find('?')?;
+    let (path, query) = path_and_query.split_at(query_start);
+    let mut token = None;
+
+    let rest = query[1..]
+        .split('&')
+        .filter(|pair| match pair.strip_prefix("token=") {
+            Some(value) => {
+                token.get_or_insert_with(|| value.to_owned());
+
+                false
+            }
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let token = token?;
+    let mut trimmed = path.to_owned();
+
+    if !rest.is_empty() {
+        trimmed.push('?');
+        trimmed.push_str(&rest);
+    }
+
+    *path_and_query = trimmed;
+
+    Some(token)
+}
+
+/// Forwards a `/cdn/` request to the CDN host. These are plain fetches that
+/// don't go through twilight and don't need a bot token.
+async fn handle_cdn(
+    state: &State,
+    host: &str,
+    path_and_query: &str,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let (mut parts, body) = request.into_parts();
+
+    if !matches!(parts.method, http::Method::GET | http::Method::HEAD) {
+        return Err(RequestError::MethodNotAllowed {
+            method: String::from(parts.method.as_str()),
+        });
+    }
+
+    parts.uri = Uri::builder()
+        .scheme("https")
+        .authority(host)
+        .path_and_query(path_and_query)
+        .build()
+        .context(BuildingCdnUri)?;
+    // The CDN must never see a bot token, and the host is the CDN's own.
+    parts.headers.remove(AUTHORIZATION);
+    parts.headers.remove(HOST);
+
+    if let Some(name) = &state.config().token_header {
+        parts.headers.remove(name);
+    }
+
+    let mut resp = state
+        .cdn
+        .request(Request::from_parts(parts, body))
+        .await
+        .context(RequestingCdn)?;
+    strip_headers(resp.headers_mut(), &state.config().strip_response_headers);
+
+    debug!("CDN {}: {}", path_and_query, resp.status());
+
+    Ok(resp)
+}
+
+/// Answers readiness checks, failing if the clients stay locked for longer than
+/// a request should ever have to wait for them.
+async fn ready_response(state: &State) -> Response<Body> {
+    for attempt in 0..READY_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        if state.client.is_unlocked() {
+            return Response::new(Body::from("ready"));
+        }
+    }
+
+    warn!("Clients are locked, reporting not ready");
+
+    let mut response = Response::new(Body::from("clients are locked"));
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+    response
+}
+
+/// Answers `OPTIONS *` with the methods that can be proxied.
+fn server_options_response(config: &Config) -> Response<Body> {
+    let allow = SUPPORTED_METHODS
+        .iter()
+        .filter(|method| {
+            config.allowed_methods.as_ref().is_none_or(|allowed| {
+                allowed.iter().any(|allowed| allowed.as_str() == **method)
+            })
+        })
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut response = Response::new(Body::empty());
+    // Method names are always valid in a header.
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_str(&allow).unwrap());
+
+    response
+}
+
+fn maintenance_response(config: &Config) -> Response<Body> {
+    let mut response = Response::new(Body::from(config.maintenance_body.clone()));
+    *response.status_mut() = config.maintenance_status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    response
+}
+
+/// Adds the configured headers to a response, combining them with any it
+/// already has as the policy says.
+fn inject_headers(headers: &mut HeaderMap, config: &Config) {
+    for (name, value) in &config.injected_headers {
+        match config.injected_header_policy {
+            HeaderPolicy::Append => {
+                headers.append(name.clone(), value.clone());
+            }
+            HeaderPolicy::Keep => {
+                headers
+                    .entry(name.clone())
+                    .or_insert_with(|| value.clone());
+            }
+            HeaderPolicy::Override => {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Logs a response's rate limit headers, for matching 429s to their buckets.
+fn log_ratelimits(method: &str, route: &str, response: &Response<Body>) {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .unwrap_or("-")
+    };
+
+    debug!(
+        method,
+        route,
+        status = response.status().as_u16(),
+        bucket = header("x-ratelimit-bucket"),
+        limit = header("x-ratelimit-limit"),
+        remaining = header("x-ratelimit-remaining"),
+        reset_after = header("x-ratelimit-reset-after"),
+        scope = header("x-ratelimit-scope"),
+        global = header("x-ratelimit-global"),
+        "Rate limit headers"
+    );
+}
+
+/// Removes every header not in `allowed`.
+fn retain_headers(headers: &mut HeaderMap, allowed: &[HeaderName]) {
+    let removed = headers
+        .keys()
+        .filter(|name| !allowed.contains(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in removed {
+        headers.remove(name);
+    }
+}
+
+/// Whether a request asks not to be answered from a cache, with `no-cache` in
+/// its `Cache-Control` or `Pragma` header.
+fn wants_revalidation(headers: &HeaderMap) -> bool {
+    let has_no_cache = |name: HeaderName| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"))
+    };
+
+    has_no_cache(CACHE_CONTROL) || has_no_cache(PRAGMA)
+}
+
+/// Removes every header in `stripped`.
+fn strip_headers(headers: &mut HeaderMap, stripped: &[HeaderName]) {
+    for name in stripped {
+        headers.remove(name);
+    }
+}
+
+/// Adds the time until the rate limit bucket resets in milliseconds, preferring
+/// `X-RateLimit-Reset-After` since `X-RateLimit-Reset` is subject to clock skew.
+fn add_reset_after_ms(headers: &mut HeaderMap) {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .and_then(|value| value.parse::<f64>().ok())
+    };
+
+    let seconds = match header("x-ratelimit-reset-after") {
+        Some(seconds) => seconds,
+        None => match header("x-ratelimit-reset") {
+            Some(reset) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0.0, |since| since.as_secs_f64());
+
+                (reset - now).max(0.0)
+            }
+            None => return,
+        },
+    };
+
+    let millis = (seconds * 1000.0).ceil() as u64;
+    headers.insert(
+        "x-proxy-ratelimit-reset-after-ms",
+        HeaderValue::from(millis),
+    );
+}
+
+/// How long Discord asked to wait before sending a request again, if it
+/// answered with a `429 Too Many Requests` and it's no longer than `max`.
+fn retry_429_delay(resp: &Response<Body>, max: Duration) -> Option<Duration> {
+    if resp.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+
+    let seconds = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|seconds| *seconds >= 0.0 && *seconds <= max.as_secs_f64())?;
+
+    // Checked again as a `Duration`, which the float may have rounded past.
+    Some(Duration::from_secs_f64(seconds)).filter(|delay| *delay <= max)
+}
+
+/// Adds up to `max_jitter_ms` to a `Retry-After` header so that clients limited at
+/// the same time don't all retry at once. The delay is only ever lengthened, so
+/// clients never retry before Discord's reset.
+///
+/// `Retry-After` only holds whole seconds, so the jittered delay is rounded up
+/// to them, as the proxy's own `Retry-After` headers are.
+fn add_retry_after_jitter(headers: &mut HeaderMap, max_jitter_ms: u64) {
+    let seconds = match headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f64>().ok())
+    {
+        Some(seconds) => seconds,
+        None => return,
+    };
+
+    let jitter_ms = rand::thread_rng().gen_range(0..=max_jitter_ms);
+    let delayed_ms = (seconds * 1000.0).ceil() as u64 + jitter_ms;
+
+    headers.insert(RETRY_AFTER, HeaderValue::from(delayed_ms.div_ceil(1000)));
+}
+
+/// Applies the method named in an `X-HTTP-Method-Override` header, which is only
+/// honoured on `POST` requests and only for methods that `POST` can stand in for.
+fn override_method(
+    method: http::Method,
+    headers: &mut HeaderMap,
+) -> Result<http::Method, RequestError> {
+    let value = match headers.remove("x-http-method-override") {
+        Some(value) => value,
+        None => return Ok(method),
+    };
+
+    let overridden = value
+        .to_str()
+        .ok()
+        .and_then(|v| http::Method::from_bytes(v.trim().to_ascii_uppercase().as_bytes()).ok());
+
+    match overridden {
+        Some(overridden)
+            if method == http::Method::POST
+                && matches!(
+                    overridden,
+                    http::Method::DELETE | http::Method::PATCH | http::Method::PUT
+                ) =>
+        {
+            Ok(overridden)
+        }
+        _ => Err(RequestError::InvalidMethodOverride {
+            method: method.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        }),
+    }
+}
+
+/// Ensures a bodied request declares a content type Discord understands.
+fn check_content_type(headers: &HeaderMap) -> Result<(), RequestError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let essence = media_type(content_type);
+
+    if essence.eq_ignore_ascii_case("application/json")
+        || essence.eq_ignore_ascii_case("multipart/form-data")
+    {
+        Ok(())
+    } else {
+        Err(RequestError::UnsupportedMediaType {
+            content_type: content_type.to_owned(),
+        })
+    }
+}
+
+/// Whether a request's body is sent to Discord as JSON. Twilight sends bodies
+/// without a `Content-Type` as JSON too.
+fn is_json(headers: &HeaderMap) -> bool {
+    match headers.get(CONTENT_TYPE) {
+        Some(value) => value.to_str().is_ok_and(|value| {
+            media_type(value).eq_ignore_ascii_case("application/json")
+        }),
+        None => true,
+    }
+}
+
+/// Strips the parameters, such as the charset, from a `Content-Type`.
+fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+fn convert_method(method: http::Method) -> Result<Method, RequestError> {
+    match method {
+        http::Method::DELETE => Ok(Method::Delete),
+        http::Method::GET => Ok(Method::Get),
+        http::Method::PATCH => Ok(Method::Patch),
+        http::Method::POST => Ok(Method::Post),
+        http::Method::PUT => Ok(Method::Put),
+        other => Err(RequestError::MethodNotAllowed { method: String::from(other.as_str()) })
+    }
+}
+
+#[cfg(feature = "expose-metrics")]
+fn handle_metrics() -> Pin<Box<dyn Future<Output = Result<Response<Body>, RequestError>> + Send>> {
+    Box::pin(async move {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::with_capacity(METRICS_SIZE.load(Ordering::Relaxed));
+
+        // Families are encoded one at a time so that one failing to encode
+        // doesn't take the others down with it.
+        for family in REGISTRY.gather() {
+            let len = buffer.len();
+
+            if let Err(e) = encoder.encode(&[family], &mut buffer) {
+                error!("error while encoding metrics: {:?}", e);
+                buffer.truncate(len);
+            }
+        }
+
+        METRICS_SIZE.store(buffer.len(), Ordering::Relaxed);
+
+        let mut response = Response::new(Body::from(buffer));
+        let content_type = format!("{}; charset=utf-8", encoder.format_type());
+
+        if let Ok(value) = HeaderValue::from_str(&content_type) {
+            response.headers_mut().insert(CONTENT_TYPE, value);
+        }
+
+        Ok(response)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::Fake;
+    use http::header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, IF_NONE_MATCH};
+    use std::net::Ipv6Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn state(config: Config, upstream: &Arc<Fake>) -> State {
+        let (_, log_filter) = reload::Layer::new(EnvFilter::new("info"));
+
+        State::new(config, Box::new(upstream.clone()), log_filter)
+    }
+
+    fn connection() -> Connection {
+        Connection::new(([127, 0, 0, 1], 4000).into(), None)
+    }
+
+    #[tokio::test]
+    async fn forwards_requests_upstream() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(
+            Response::builder()
+                .header("x-ratelimit-remaining", "4")
+                .body(Body::from(r#"{"id":"1"}"#))
+                .unwrap(),
+        );
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!(
+                "/api/v{}/channels/123/messages?wait=true",
+                API_VERSION
+            ))
+            .header(AUTHORIZATION, "Bot MTIz.abc.def")
+            .header(CONTENT_TYPE, "application/json")
+            .header("x-audit-log-reason", "tidying up")
+            .body(Body::from(r#"{"content":"hi"}"#))
+            .unwrap();
+
+        let response = handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "4");
+
+        let requests = upstream.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let sent = &requests[0];
+        assert_eq!(sent.method, Method::Post);
+        assert_eq!(sent.path, Path::ChannelsIdMessages(123));
+        assert_eq!(sent.path_str, "channels/123/messages?wait=true");
+        assert_eq!(sent.body.as_deref(), Some(&br#"{"content":"hi"}"#[..]));
+
+        let headers = sent.headers.as_ref().unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bot MTIz.abc.def");
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+        assert_eq!(headers["x-audit-log-reason"], "tidying up");
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_methods() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let request = Request::builder()
+            .method(http::Method::HEAD)
+            .uri(format!("/api/v{}/gateway", API_VERSION))
+            .body(Body::empty())
+            .unwrap();
+
+        let result = handle_request(&state, &connection(), request).await;
+        assert!(matches!(result, Err(RequestError::MethodNotAllowed { .. })));
+        assert!(upstream.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_errors_with_a_status() {
+        let upstream = Arc::new(Fake::default());
+        let state = Arc::new(state(Config::from_env().unwrap(), &upstream));
+        let request = Request::builder()
+            .uri(format!("/api/v{}/nonsense", API_VERSION))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn jitters_retry_after_in_whole_seconds() {
+        for (retry_after, lowest) in &[("2", 2), ("0.25", 1)] {
+            for _ in 0..100 {
+                let mut headers = HeaderMap::new();
+                headers.insert(RETRY_AFTER, HeaderValue::from_static(retry_after));
+                add_retry_after_jitter(&mut headers, 1500);
+
+                let jittered = headers[RETRY_AFTER].to_str().unwrap();
+                let seconds = jittered.parse::<u64>().unwrap();
+                assert!((*lowest..=lowest + 2).contains(&seconds), "{}", jittered);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_long_uris() {
+        let upstream = Arc::new(Fake::default());
+        let at_limit = format!("/api/v{}/channels/123/messages?limit=5", API_VERSION);
+        let past_limit = format!("/api/v{}/channels/123/messages?limit=50", API_VERSION);
+        let mut config = Config::from_env().unwrap();
+        config.max_uri_length = Some(at_limit.len());
+        let state = state(config, &upstream);
+
+        let request = Request::builder()
+            .uri(&past_limit)
+            .body(Body::empty())
+            .unwrap();
+        match handle_request(&state, &connection(), request).await {
+            Err(RequestError::UriTooLong { length, limit }) => {
+                assert_eq!((length, limit), (at_limit.len() + 1, at_limit.len()));
+            }
+            other => panic!("long URI was answered with {:?}", other),
+        }
+        assert!(upstream.requests.lock().unwrap().is_empty());
+
+        let request = Request::builder()
+            .uri(&at_limit)
+            .body(Body::empty())
+            .unwrap();
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+        assert_eq!(upstream.requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn trims_only_a_leading_api_prefix() {
+        let prefix = "/api/v9/";
+
+        assert_eq!(trim_api_prefix("/api/v9/gateway", prefix), "gateway");
+        assert_eq!(
+            trim_api_prefix("/api/v9/gateway?x=/api/v9/", prefix),
+            "gateway?x=/api/v9/"
+        );
+        assert_eq!(
+            trim_api_prefix("/gateway?x=/api/v9/", prefix),
+            "/gateway?x=/api/v9/"
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_paths_with_and_without_queries() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+
+        for (uri, path_str) in &[
+            ("/guilds/1/members", "guilds/1/members"),
+            ("/guilds/1/members?", "guilds/1/members?"),
+            ("/guilds/1/members?limit=5", "guilds/1/members?limit=5"),
+        ] {
+            let request = Request::builder()
+                .uri(format!("/api/v{}{}", API_VERSION, uri))
+                .body(Body::empty())
+                .unwrap();
+            handle_request(&state, &connection(), request)
+                .await
+                .unwrap();
+
+            let sent = upstream.requests.lock().unwrap().pop().unwrap();
+            assert_eq!(sent.path, Path::GuildsIdMembers(1));
+            assert_eq!(sent.path_str, *path_str);
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_chunked_bodies_with_their_length() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let (mut sender, body) = Body::channel();
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/api/v{}/channels/123/messages", API_VERSION))
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(body)
+            .unwrap();
+
+        tokio::spawn(async move {
+            for chunk in &["{\"content\":", "\"hi\"}"] {
+                sender
+                    .send_data(hyper::body::Bytes::from(*chunk))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+
+        let sent = upstream.requests.lock().unwrap().pop().unwrap();
+        let headers = sent.headers.unwrap();
+        assert_eq!(sent.body.as_deref(), Some(&b"{\"content\":\"hi\"}"[..]));
+        assert!(!headers.contains_key(TRANSFER_ENCODING));
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn switches_maintenance_mode_at_runtime() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.admin_secret = Some("secret".to_owned());
+        let maintenance_status = config.maintenance_status;
+        let state = Arc::new(state(config, &upstream));
+        let send = |method, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("x-admin-secret", "secret")
+                .body(Body::empty())
+                .unwrap();
+
+            handle(state.clone(), Arc::new(connection()), request)
+        };
+        let gateway = format!("/api/v{}/gateway", API_VERSION);
+
+        let response = send(http::Method::POST, "/admin/maintenance?enabled=true")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(http::Method::GET, &gateway).await.unwrap();
+        assert_eq!(response.status(), maintenance_status);
+        assert!(upstream.requests.lock().unwrap().is_empty());
+
+        // Health checks are answered whatever the mode.
+        let response = send(http::Method::GET, "/health").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(http::Method::POST, "/admin/maintenance?enabled=no")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        send(http::Method::POST, "/admin/maintenance?enabled=0")
+            .await
+            .unwrap();
+        let response = send(http::Method::GET, &gateway).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(upstream.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn passes_on_allowed_response_headers_only() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(
+            Response::builder()
+                .header("x-ratelimit-remaining", "4")
+                .header("x-envoy-upstream-service-time", "12")
+                .body(Body::empty())
+                .unwrap(),
+        );
+        let mut config = Config::from_env().unwrap();
+        config.response_header_allowlist =
+            Some(vec![HeaderName::from_static("x-ratelimit-remaining")]);
+        let state = state(config, &upstream);
+
+        let response = handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+        let names = response.headers().keys().collect::<Vec<_>>();
+
+        assert_eq!(names, ["x-ratelimit-remaining"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_methods_not_allowed() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.allowed_methods = Some(vec![http::Method::GET]);
+        let state = state(config, &upstream);
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(format!("/api/v{}/channels/123", API_VERSION))
+            .body(Body::empty())
+            .unwrap();
+
+        let result = handle_request(&state, &connection(), request).await;
+        assert!(matches!(result, Err(RequestError::MethodNotAllowed { .. })));
+
+        handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+        assert_eq!(upstream.requests.lock().unwrap().len(), 1);
+    }
+
+    fn get_gateway() -> Request<Body> {
+        Request::builder()
+            .uri(format!("/api/v{}/gateway", API_VERSION))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[cfg(feature = "expose-metrics")]
+    #[tokio::test]
+    async fn labels_metrics_as_prometheus_text() {
+        let response = handle_metrics().await.unwrap();
+
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_conditional_requests_through() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(
+            Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(ETAG, "\"abc\"")
+                .body(Body::empty())
+                .unwrap(),
+        );
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let request = Request::builder()
+            .uri(format!("/api/v{}/guilds/1", API_VERSION))
+            .header(IF_NONE_MATCH, "\"abc\"")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], "\"abc\"");
+
+        let sent = upstream.requests.lock().unwrap().pop().unwrap();
+        assert_eq!(sent.headers.unwrap()[IF_NONE_MATCH], "\"abc\"");
+    }
+
+    #[tokio::test]
+    async fn rejects_unusable_authorization() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+
+        let values = [
+            HeaderValue::from_static(""),
+            HeaderValue::from_static("   "),
+            // Not UTF-8, so twilight can't take it as a token.
+            HeaderValue::from_bytes(b"Bot \xff").unwrap(),
+        ];
+
+        for value in values {
+            let mut request = get_gateway();
+            request.headers_mut().insert(AUTHORIZATION, value);
+
+            let result = handle_request(&state, &connection(), request).await;
+            assert!(matches!(result, Err(RequestError::InvalidAuthorization)));
+            assert_eq!(result.unwrap_err().status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(upstream.requests.lock().unwrap().is_empty());
+
+        // Without the header the global token is used.
+        handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+
+        let sent = upstream.requests.lock().unwrap().pop().unwrap();
+        assert!(!sent.headers.unwrap().contains_key(AUTHORIZATION));
+    }
+
+    #[tokio::test]
+    async fn limits_the_tokens_used_on_a_connection() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.max_tokens_per_connection = Some(2);
+        let state = state(config, &upstream);
+        let connection = connection();
+        let send = |token: String| {
+            let mut request = get_gateway();
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, HeaderValue::from_str(&token).unwrap());
+
+            handle_request(&state, &connection, request)
+        };
+
+        assert!(send("Bot MTIz.a".to_owned()).await.is_ok());
+        assert!(send("Bot MTIz.b".to_owned()).await.is_ok());
+
+        for n in 0..10 {
+            let result = send(format!("Bot MTIz.scan{}", n)).await;
+            assert!(matches!(
+                result,
+                Err(RequestError::TooManyTokens { limit: 2 })
+            ));
+        }
+
+        // Tokens already used go on working, and the rejected ones weren't kept.
+        assert!(send("Bot MTIz.a".to_owned()).await.is_ok());
+        assert_eq!(connection.tokens.lock().unwrap().len(), 2);
+        assert_eq!(upstream.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_without_a_proxy_header_in_time() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.proxy_protocol = true;
+        config.inbound_idle_timeout = Some(Duration::from_millis(100));
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_connections(
+            listener,
+            Arc::new(state(config, &upstream)),
+        ));
+
+        let mut silent = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut buffer = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut buffer));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+
+        let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 1.2.3.4 5.6.7.8 1234 80\r\nGET /health HTTP/1.1\r\nhost: proxy\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buffer));
+        read.await.unwrap().unwrap();
+
+        assert!(buffer.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(buffer.ends_with(b"ok"));
+    }
+
+    #[tokio::test]
+    async fn forwards_each_requests_own_token() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+
+        // The same bot before and after its token was rotated.
+        for token in &["Bot MTIz.old.token", "Bot MTIz.new.token"] {
+            let mut request = get_gateway();
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, HeaderValue::from_static(token));
+            handle_request(&state, &connection(), request)
+                .await
+                .unwrap();
+
+            let sent = upstream.requests.lock().unwrap().pop().unwrap();
+            assert_eq!(sent.headers.unwrap()[AUTHORIZATION], *token);
+        }
+    }
+
+    #[test]
+    fn rebuilt_clients_use_rotated_tokens() {
+        let name = "MAIN_TEST_ROTATED_TOKEN";
+        let source = TokenSource::Env(name.to_owned());
+        env::set_var(name, "old");
+        let last = Mutex::new(source.load().unwrap());
+
+        env::set_var(name, "new");
+        assert_eq!(reload_tokens(&source, &last), "new");
+
+        // Tokens that can't be read keep the clients on the last ones read.
+        env::remove_var(name);
+        assert_eq!(reload_tokens(&source, &last), "new");
+    }
+
+    #[tokio::test]
+    async fn names_the_method_and_path_of_invalid_paths() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let request = Request::builder()
+            .method(http::Method::PATCH)
+            .uri(format!("/api/v{}/nonsense/route?x=1", API_VERSION))
+            .body(Body::empty())
+            .unwrap();
+
+        let why = handle_request(&state, &connection(), request)
+            .await
+            .unwrap_err();
+        assert!(matches!(why, RequestError::InvalidPath { .. }));
+        assert_eq!(why.to_string(), "invalid path: PATCH nonsense/route");
+        assert_eq!(why.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn answers_options_for_the_server_itself() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.allowed_methods = Some(vec![http::Method::GET, http::Method::POST]);
+        let state = state(config, &upstream);
+        let request = Request::builder()
+            .method(http::Method::OPTIONS)
+            .uri("*")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ALLOW], "GET, POST");
+        assert!(upstream.requests.lock().unwrap().is_empty());
+
+        let response = server_options_response(&Config::from_env().unwrap());
+        assert_eq!(response.headers()[ALLOW], "DELETE, GET, PATCH, POST, PUT");
+    }
+
+    #[tokio::test]
+    async fn asks_for_the_body_once_the_request_is_accepted() {
+        let upstream = Arc::new(Fake::default());
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_connections(
+            listener,
+            Arc::new(state(Config::from_env().unwrap(), &upstream)),
+        ));
+        let head = format!(
+            "POST /api/v{}/channels/1/messages HTTP/1.1\r\nhost: proxy\r\ncontent-type: application/json\r\ncontent-length: 2\r\nexpect: 100-continue\r\nconnection: close\r\n\r\n",
+            API_VERSION
+        );
+
+        let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+        client.write_all(head.as_bytes()).await.unwrap();
+        let interim = b"HTTP/1.1 100 Continue\r\n\r\n";
+        let mut buffer = vec![0; interim.len()];
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buffer));
+        read.await.unwrap().unwrap();
+        assert_eq!(buffer, interim);
+        // Nothing is forwarded before the body arrives.
+        assert!(upstream.requests.lock().unwrap().is_empty());
+
+        client.write_all(b"{}").await.unwrap();
+        buffer.clear();
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buffer));
+        read.await.unwrap().unwrap();
+
+        assert!(buffer.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        let sent = upstream.requests.lock().unwrap().pop().unwrap();
+        assert_eq!(sent.body.as_deref(), Some(&b"{}"[..]));
+    }
+
+    #[tokio::test]
+    async fn rejects_chunked_bodies_past_the_limit() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.max_body_size = Some(8);
+        let state = state(config, &upstream);
+        let (mut sender, body) = Body::channel();
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/api/v{}/channels/123/messages", API_VERSION))
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(body)
+            .unwrap();
+
+        tokio::spawn(async move {
+            for chunk in &["{\"content\":", "\"hi\"}"] {
+                if sender
+                    .send_data(hyper::body::Bytes::from(*chunk))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let result = handle_request(&state, &connection(), request).await;
+        assert!(matches!(
+            result,
+            Err(RequestError::PayloadTooLarge { limit: 8 })
+        ));
+        assert!(upstream.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_acting_as_a_bot_without_authorization() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let mut request = get_gateway();
+        request.headers_mut().insert(
+            ACT_AS_TOKEN,
+            HeaderValue::from_static("Bot MTIz.other.token"),
+        );
+
+        let result = handle_request(&state, &connection(), request).await;
+
+        assert!(matches!(
+            result,
+            Err(RequestError::ActAsWithoutAuthorization)
+        ));
+        assert!(upstream.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn times_only_requests_that_reached_discord() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.upstream_duration_header = true;
+        config.idempotency_ttl = Some(Duration::from_secs(60));
+        let state = state(config, &upstream);
+        let connection = connection();
+        let send = || {
+            let request = Request::builder()
+                .method(http::Method::POST)
+                .uri(format!("/api/v{}/channels/1/messages", API_VERSION))
+                .header(IDEMPOTENCY_KEY, "once")
+                .body(Body::from("{}"))
+                .unwrap();
+
+            handle_request(&state, &connection, request)
+        };
+
+        let first = send().await.unwrap();
+        let replayed = send().await.unwrap();
+
+        assert!(first.headers().contains_key("x-upstream-duration-ms"));
+        assert!(!replayed.headers().contains_key("x-upstream-duration-ms"));
+        assert_eq!(upstream.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_bot_tokens_without_a_bot_id() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.reject_invalid_bot_id = true;
+        let state = state(config, &upstream);
+
+        for (token, valid) in &[
+            ("Bot .abc.def", false),
+            ("Bot !!!.abc.def", false),
+            ("Bot MTIz.abc.def", true),
+            // Only bot tokens are expected to start with an id.
+            ("Bearer abc", true),
+        ] {
+            let mut request = get_gateway();
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, HeaderValue::from_static(token));
+            let result = handle_request(&state, &connection(), request).await;
+
+            if *valid {
+                assert!(result.is_ok(), "{} was rejected", token);
+            } else {
+                assert!(matches!(result, Err(RequestError::InvalidBotId)));
+            }
+        }
+
+        assert_eq!(upstream.requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        let parse = |host| listen_address(host, 80).unwrap();
+
+        assert_eq!(parse("0.0.0.0"), SocketAddr::from(([0, 0, 0, 0], 80)));
+        assert_eq!(parse("::1"), SocketAddr::from((Ipv6Addr::LOCALHOST, 80)));
+        assert_eq!(parse("[::1]"), SocketAddr::from((Ipv6Addr::LOCALHOST, 80)));
+
+        let scoped = parse("[fe80::1%2]");
+        match scoped {
+            SocketAddr::V6(address) => {
+                assert_eq!(address.ip(), &"fe80::1".parse::<Ipv6Addr>().unwrap());
+                assert_eq!(address.scope_id(), 2);
+            }
+            SocketAddr::V4(_) => panic!("{} isn't IPv6", scoped),
+        }
+
+        assert!(listen_address("127.0.0.1%2", 80).is_err());
+        assert!(listen_address("fe80::1%not/an/interface", 80).is_err());
+        assert!(listen_address("localhost", 80).is_err());
+    }
+
+    #[tokio::test]
+    async fn strips_infrastructure_headers_from_responses() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(
+            Response::builder()
+                .header("cf-ray", "abc-FRA")
+                .header("set-cookie", "__cfruid=abc")
+                .header("via", "1.1 google")
+                .header("x-ratelimit-limit", "5")
+                .body(Body::empty())
+                .unwrap(),
+        );
+        let state = state(Config::from_env().unwrap(), &upstream);
+
+        let response = handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+        let names = response.headers().keys().collect::<Vec<_>>();
+
+        assert_eq!(names, ["x-ratelimit-limit"]);
+    }
+
+    #[test]
+    fn reads_no_cache_requests() {
+        let wants = |name, value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+
+            wants_revalidation(&headers)
+        };
+
+        assert!(wants(CACHE_CONTROL, "no-cache"));
+        assert!(wants(CACHE_CONTROL, "max-age=0, No-Cache"));
+        assert!(wants(PRAGMA, "no-cache"));
+        assert!(!wants(CACHE_CONTROL, "no-store"));
+        assert!(!wants(CACHE_CONTROL, "no-cache-please"));
+        assert!(!wants_revalidation(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn revalidates_cached_gateway_bot_responses() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(Response::new(Body::from("first")));
+        upstream.respond(Response::new(Body::from("second")));
+        let mut config = Config::from_env().unwrap();
+        config.gateway_bot_cache = Some(Duration::from_secs(60));
+        let state = state(config, &upstream);
+        let gateway_bot = |cache_control| {
+            let mut request = Request::builder()
+                .uri(format!("/api/v{}/gateway/bot", API_VERSION))
+                .body(Body::empty())
+                .unwrap();
+
+            if let Some(value) = cache_control {
+                request
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static(value));
+            }
+
+            request
+        };
+
+        for (cache_control, expected) in &[
+            (None, "first"),
+            (None, "first"),
+            (Some("no-cache"), "second"),
+            (None, "second"),
+        ] {
+            let response = handle_request(&state, &connection(), gateway_bot(*cache_control))
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            assert_eq!(body, *expected);
+        }
+
+        assert_eq!(upstream.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn times_out_bodies_that_stall() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.read_timeout = Some(Duration::from_millis(50));
+        let state = Arc::new(state(config, &upstream));
+        let (mut sender, body) = Body::channel();
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("/api/v{}/channels/1/messages", API_VERSION))
+            .body(body)
+            .unwrap();
+
+        // Part of the body arrives, but the rest never does.
+        sender.send_data("{\"content\":".into()).await.unwrap();
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(upstream.requests.lock().unwrap().is_empty());
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn logs_slow_requests_with_their_token() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.slow_request = Some(Duration::from_nanos(1));
+        let state = state(config, &upstream);
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let writer = logged.clone();
+        let subscriber = fmt()
+            .with_writer(move || LogWriter(writer.clone()))
+            .with_ansi(false)
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+        let token = "Bot MTIz.slow.token";
+        let mut request = get_gateway();
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_static(token));
+
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+
+        let logged = String::from_utf8(logged.lock().unwrap().clone()).unwrap();
+        let field = format!("token=\"{:016x}\"", token::hash(token.as_bytes()));
+        let line = logged.lines().find(|line| line.contains("slow_request"));
+        assert!(line.unwrap().contains(&field), "{}", logged);
+    }
+
+    /// Collects what's logged, for checking what was.
+    struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogWriter {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_the_accept_header_unchanged() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+        let accept = "application/json, text/plain;q=0.5";
+        let mut request = get_gateway();
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static(accept));
+
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+
+        let sent = upstream.requests.lock().unwrap().pop().unwrap();
+        assert_eq!(sent.headers.unwrap()[ACCEPT], accept);
+    }
+
+    #[tokio::test]
+    async fn limits_routes_by_variant_rather_than_name() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        // Fetching and sending messages are both named "Channel message".
+        config.route_concurrency = vec![("ChannelsIdMessagesId".to_owned(), 0)]
+            .into_iter()
+            .collect();
+        let state = state(config, &upstream);
+        let connection = connection();
+        let send = |method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("/api/v{}/{}", API_VERSION, path))
+                .body(Body::empty())
+                .unwrap();
+
+            handle_request(&state, &connection, request)
+        };
+
+        let result = send(http::Method::GET, "channels/1/messages/2").await;
+        assert!(matches!(
+            result,
+            Err(RequestError::RouteConcurrency { limit: 0, .. })
+        ));
+        assert!(send(http::Method::POST, "channels/1/messages")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn sheds_routes_by_variant_rather_than_name() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.shed_threshold = Some(0);
+        // A channel's webhooks and a webhook are both named "Webhook".
+        config.route_priorities = vec![("ChannelsIdWebhooks".to_owned(), 0)]
+            .into_iter()
+            .collect();
+        let state = state(config, &upstream);
+        let connection = connection();
+        let _in_flight = state.stats.start();
+        let send = |path: &str| {
+            let request = Request::builder()
+                .uri(format!("/api/v{}/{}", API_VERSION, path))
+                .body(Body::empty())
+                .unwrap();
+
+            handle_request(&state, &connection, request)
+        };
+
+        let result = send("channels/1/webhooks").await;
+        assert!(matches!(
+            result,
+            Err(RequestError::Shed { route: "Webhook" })
+        ));
+        assert!(send("webhooks/1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn forwards_requests_in_threads_as_in_channels() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+        // Threads are channels, so what's sent in one takes the channel routes
+        // and the thread's own ratelimits.
+        let thread_id = 900;
+
+        for (method, route, path, name) in &[
+            (
+                http::Method::POST,
+                "messages",
+                Path::ChannelsIdMessages(thread_id),
+                "Channel message",
+            ),
+            (
+                http::Method::POST,
+                "typing",
+                Path::ChannelsIdTyping(thread_id),
+                "Typing indicator",
+            ),
+            (
+                http::Method::GET,
+                "pins",
+                Path::ChannelsIdPins(thread_id),
+                "Channel pins",
+            ),
+        ] {
+            let path_str = format!("channels/{}/{}", thread_id, route);
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("/api/v{}/{}", API_VERSION, path_str))
+                .body(Body::empty())
+                .unwrap();
+            handle_request(&state, &connection(), request)
+                .await
+                .unwrap();
+
+            let sent = upstream.requests.lock().unwrap().pop().unwrap();
+            assert_eq!(sent.path, *path);
+            assert_eq!(sent.path_str, path_str);
+            assert_eq!(path_name(&sent.path), *name);
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_interaction_followups_with_files() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.strict_content_type = true;
+        let state = state(config, &upstream);
+        let content_type = "multipart/form-data; boundary=xyz";
+        let body =
+            "--xyz\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\r\n{}\r\n--xyz--";
+        // Followups to an interaction are made through its application's
+        // webhook, with the interaction's token.
+        let request = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!(
+                "/api/v{}/webhooks/123/interaction-token",
+                API_VERSION
+            ))
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+
+        let sent = upstream.requests.lock().unwrap().pop().unwrap();
+        assert_eq!(sent.method, Method::Post);
+        assert_eq!(sent.path, Path::WebhooksId(123));
+        assert_eq!(sent.path_str, "webhooks/123/interaction-token");
+        assert_eq!(sent.body.as_deref(), Some(body.as_bytes()));
+        assert_eq!(sent.headers.unwrap()[CONTENT_TYPE], content_type);
+    }
+
+    #[tokio::test]
+    async fn forwards_thread_routes() {
+        let upstream = Arc::new(Fake::default());
+        let state = state(Config::from_env().unwrap(), &upstream);
+
+        for (method, path_str, path, name) in &[
+            (
+                http::Method::POST,
+                "channels/1/threads",
+                Path::ChannelsId(1),
+                "Channel threads",
+            ),
+            (
+                http::Method::POST,
+                "channels/1/messages/2/threads",
+                Path::ChannelsId(1),
+                "Thread from message",
+            ),
+            (
+                http::Method::PUT,
+                "channels/1/thread-members/@me",
+                Path::ChannelsId(1),
+                "Thread member",
+            ),
+            (
+                http::Method::GET,
+                "guilds/1/threads/active",
+                Path::GuildsId(1),
+                "Active guild threads",
+            ),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("/api/v{}/{}", API_VERSION, path_str))
+                .body(Body::empty())
+                .unwrap();
+            handle_request(&state, &connection(), request)
+                .await
+                .unwrap();
+
+            let sent = upstream.requests.lock().unwrap().pop().unwrap();
+            assert_eq!(sent.path, *path);
+            assert_eq!(sent.path_str, *path_str);
+
+            let method = convert_method(method.clone()).unwrap();
+            assert_eq!(parse_path(method, path_str).unwrap().name, *name);
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_command_and_interaction_routes() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.reject_unknown_routes = true;
+        let state = state(config, &upstream);
+
+        for (method, path_str, path, name) in &[
+            (
+                http::Method::POST,
+                "applications/1/commands",
+                Path::OauthApplicationsMe,
+                "Global application commands",
+            ),
+            (
+                http::Method::PUT,
+                "applications/1/guilds/2/commands",
+                Path::GuildsId(2),
+                "Guild application commands",
+            ),
+            (
+                http::Method::POST,
+                "interactions/1/interaction-token/callback",
+                Path::WebhooksId(1),
+                "Interaction callback",
+            ),
+            (
+                http::Method::PATCH,
+                "webhooks/1/interaction-token/messages/@original",
+                Path::WebhooksIdTokenMessageId(1),
+                "Webhook message",
+            ),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("/api/v{}/{}", API_VERSION, path_str))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            handle_request(&state, &connection(), request)
+                .await
+                .unwrap();
+
+            let sent = upstream.requests.lock().unwrap().pop().unwrap();
+            assert_eq!(sent.path, *path);
+            assert_eq!(sent.path_str, *path_str);
+            assert_eq!(sent.body.as_deref(), Some(&b"{}"[..]));
+
+            let method = convert_method(method.clone()).unwrap();
+            assert_eq!(parse_path(method, path_str).unwrap().name, *name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reloads_settings_from_the_config_file() {
+        let upstream = Arc::new(Fake::default());
+        // The handle only reloads while its layer is alive.
+        let (_layer, log_filter) = reload::Layer::new(EnvFilter::new("info"));
+        let state = State::new(
+            Config::from_env().unwrap(),
+            Box::new(upstream.clone()),
+            log_filter,
+        );
+        let path = env::temp_dir().join(format!("reload-test-{}.env", process::id()));
+        fs::write(&path, "RUST_LOG=debug\nMAX_BODY_SIZE=1234\n").unwrap();
+        let before = env::var_os("RUST_LOG");
+        let mut file = HashMap::new();
+
+        let result = reload(&state, Some(&path), &mut file);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let filter = state.log_filter.with_current(ToString::to_string);
+        assert_eq!(filter.unwrap(), "debug");
+        assert_eq!(state.config().max_body_size, Some(1234));
+        assert_eq!(file["RUST_LOG"], "debug");
+        // The file's variables are read without being set in the environment.
+        assert_eq!(env::var_os("RUST_LOG"), before);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_admin_log_filter_until_rust_log_changes() {
+        let upstream = Arc::new(Fake::default());
+        let (_layer, log_filter) = reload::Layer::new(EnvFilter::new("info"));
+        let state = State::new(
+            Config::from_env().unwrap(),
+            Box::new(upstream.clone()),
+            log_filter,
+        );
+        let path = env::temp_dir().join(format!("reload-admin-test-{}.env", process::id()));
+        let current = || state.log_filter.with_current(ToString::to_string).unwrap();
+        let mut file = HashMap::new();
+        // As `POST /admin/log-level` sets it.
+        state.log_filter.reload(EnvFilter::new("trace")).unwrap();
+
+        fs::write(&path, "MAX_BODY_SIZE=1234\n").unwrap();
+        let unchanged = reload(&state, Some(&path), &mut file);
+        let kept = current();
+        fs::write(&path, "RUST_LOG=warn\n").unwrap();
+        let changed = reload(&state, Some(&path), &mut file);
+        fs::remove_file(&path).unwrap();
+
+        unchanged.unwrap();
+        changed.unwrap();
+        assert_eq!(kept, "trace");
+        assert_eq!(current(), "warn");
+    }
+
+    fn batch(entries: serde_json::Value) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::POST)
+            .uri("/batch")
+            .header(AUTHORIZATION, "Bot MTIz.batch.token")
+            .body(Body::from(entries.to_string()))
+            .unwrap()
+    }
+
+    async fn batch_entries(response: Response<Body>) -> Vec<serde_json::Value> {
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn batching_state(upstream: &Arc<Fake>) -> Arc<State> {
+        let mut config = Config::from_env().unwrap();
+        config.enable_batch = true;
+
+        Arc::new(state(config, upstream))
+    }
+
+    #[tokio::test]
+    async fn rejects_batches_with_a_malformed_request_whole() {
+        let upstream = Arc::new(Fake::default());
+        let state = batching_state(&upstream);
+        let gateway = format!("/api/v{}/gateway", API_VERSION);
+
+        for malformed in &[
+            serde_json::json!({ "method": "GET" }),
+            serde_json::json!({ "method": "GET", "path": "https://example.com/" }),
+            serde_json::json!({ "method": "GET", "path": gateway, "headers": { "x-n": 1 } }),
+        ] {
+            let request = batch(serde_json::json!([
+                { "method": "GET", "path": gateway },
+                malformed,
+            ]));
+            let response = handle(state.clone(), Arc::new(connection()), request)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        assert!(upstream.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_each_request_in_a_batch_on_its_own() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"url":"wss://gateway.discord.gg"}"#))
+                .unwrap(),
+        );
+        let state = batching_state(&upstream);
+        let gateway = format!("/api/v{}/gateway", API_VERSION);
+        let request = batch(serde_json::json!([
+            { "method": "GET", "path": gateway },
+            { "method": "OPTIONS", "path": gateway },
+            { "method": "GET", "path": format!("/api/v{}/nonsense", API_VERSION) },
+        ]));
+
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+        let entries = batch_entries(response).await;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["status"], 200);
+        assert_eq!(entries[0]["body"]["url"], "wss://gateway.discord.gg");
+        assert_eq!(entries[1]["status"], 405);
+        assert_eq!(entries[2]["status"], 400);
+        assert_eq!(upstream.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sends_the_batchs_authorization_unless_a_request_has_its_own() {
+        let upstream = Arc::new(Fake::default());
+        let state = batching_state(&upstream);
+        let gateway = format!("/api/v{}/gateway", API_VERSION);
+        let request = batch(serde_json::json!([
+            { "method": "GET", "path": gateway },
+            {
+                "method": "GET",
+                "path": gateway,
+                "headers": { "authorization": "Bot NDU2.own.token" },
+            },
+        ]));
+
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+        assert_eq!(batch_entries(response).await.len(), 2);
+
+        let requests = upstream.requests.lock().unwrap();
+        let tokens = requests
+            .iter()
+            .map(|sent| sent.headers.as_ref().unwrap()[AUTHORIZATION].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, ["Bot MTIz.batch.token", "Bot NDU2.own.token"]);
+    }
+
+    #[tokio::test]
+    async fn limits_the_requests_in_a_batch() {
+        let upstream = Arc::new(Fake::default());
+        let state = batching_state(&upstream);
+        let gateway = serde_json::json!({
+            "method": "GET",
+            "path": format!("/api/v{}/gateway", API_VERSION),
+        });
+
+        let request = batch(serde_json::json!(vec![
+            gateway.clone();
+            batch::MAX_REQUESTS + 1
+        ]));
+        let response = handle(state.clone(), Arc::new(connection()), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(upstream.requests.lock().unwrap().is_empty());
+
+        let request = batch(serde_json::json!(vec![gateway; batch::MAX_REQUESTS]));
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+        assert_eq!(batch_entries(response).await.len(), batch::MAX_REQUESTS);
+        assert_eq!(upstream.requests.lock().unwrap().len(), batch::MAX_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn forwards_batches_as_requests_unless_enabled() {
+        let upstream = Arc::new(Fake::default());
+        let state = Arc::new(state(Config::from_env().unwrap(), &upstream));
+        let request = batch(serde_json::json!([
+            { "method": "GET", "path": format!("/api/v{}/gateway", API_VERSION) },
+        ]));
+
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(upstream.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_batches_at_their_deadline_with_the_responses_so_far() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(Response::new(Body::from("fast")));
+        upstream.respond_after(Duration::from_secs(10), Response::new(Body::from("slow")));
+        let state = batching_state(&upstream);
+        let mut request = batch(serde_json::json!([
+            { "method": "GET", "path": format!("/api/v{}/gateway", API_VERSION) },
+            { "method": "GET", "path": format!("/api/v{}/gateway/bot", API_VERSION) },
+        ]));
+        request
+            .headers_mut()
+            .insert(REQUEST_DEADLINE, HeaderValue::from_static("100"));
+
+        let started = Instant::now();
+        let response = handle(state, Arc::new(connection()), request)
+            .await
+            .unwrap();
+        let entries = batch_entries(response).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(entries[0]["status"], 200);
+        assert_eq!(entries[0]["body"], "fast");
+        assert!(entries[0].get("timed_out").is_none());
+        assert_eq!(entries[1]["status"], 504);
+        assert_eq!(entries[1]["timed_out"], true);
+    }
+
+    #[tokio::test]
+    async fn passes_large_streamed_responses_on_as_they_arrive() {
+        let upstream = Arc::new(Fake::default());
+        let (mut sender, members) = Body::channel();
+        upstream.respond(Response::new(members));
+        let mut config = Config::from_env().unwrap();
+        config.response_gzip = Some(1);
+        config.single_flight = true;
+        let state = state(config, &upstream);
+        let request = Request::builder()
+            .uri(format!("/api/v{}/guilds/1/members", API_VERSION))
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+
+        // A buffered response would wait for a body that's only sent once
+        // it's answered.
+        let connection = connection();
+        let handling = handle_request(&state, &connection, request);
+        let response = tokio::time::timeout(Duration::from_secs(5), handling)
+            .await
+            .expect("the response was buffered")
+            .unwrap();
+        let mut body = response.into_body();
+        let chunk = vec![b'x'; 1024 * 1024];
+        let mut received = 0;
+
+        // Each megabyte reaches the client before Discord sends the next.
+        for _ in 0..8 {
+            sender.send_data(chunk.clone().into()).await.unwrap();
+
+            let mut read = 0;
+            while read < chunk.len() {
+                read += body.data().await.unwrap().unwrap().len();
+            }
+            received += read;
+        }
+        drop(sender);
+
+        assert!(body.data().await.is_none());
+        assert_eq!(received, 8 * chunk.len());
+    }
+
+    #[test]
+    fn streams_only_large_list_responses() {
+        assert!(streams_response(&Path::GuildsIdMembers(1)));
+        assert!(streams_response(&Path::GuildsIdBans(1)));
+        assert!(streams_response(&Path::GuildsIdAuditLogs(1)));
+        assert!(!streams_response(&Path::GuildsId(1)));
+        assert!(!streams_response(&Path::GatewayBot));
+    }
+
+    #[tokio::test]
+    async fn streams_responses_without_gzipping_them() {
+        let upstream = Arc::new(Fake::default());
+        let members = format!("[{}]", vec!["{}"; 100].join(","));
+        upstream.respond(Response::new(Body::from(members.clone())));
+        upstream.respond(Response::new(Body::from(members.clone())));
+        let mut config = Config::from_env().unwrap();
+        config.response_gzip = Some(1);
+        let state = state(config, &upstream);
+
+        for (route, gzipped) in &[("members", false), ("roles", true)] {
+            let request = Request::builder()
+                .uri(format!("/api/v{}/guilds/1/{}", API_VERSION, route))
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+            let response = handle_request(&state, &connection(), request)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                response.headers().contains_key(CONTENT_ENCODING),
+                *gzipped,
+                "{}",
+                route
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_the_token_from_the_token_header() {
+        let upstream = Arc::new(Fake::default());
+        let mut config = Config::from_env().unwrap();
+        config.token_header = Some(HeaderName::from_static("x-discord-token"));
+        let state = state(config, &upstream);
+
+        let mut request = get_gateway();
+        let headers = request.headers_mut();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic gateway"));
+        headers.insert("x-discord-token", HeaderValue::from_static("Bot MTIz.abc"));
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+
+        // Without the header, the other system's Authorization isn't passed on
+        // and the global token is used.
+        let mut request = get_gateway();
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, HeaderValue::from_static("Basic gateway"));
+        handle_request(&state, &connection(), request)
+            .await
+            .unwrap();
+
+        let requests = upstream.requests.lock().unwrap();
+        let headers = requests[0].headers.as_ref().unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bot MTIz.abc");
+        assert!(!headers.contains_key("x-discord-token"));
+
+        let headers = requests[1].headers.as_ref().unwrap();
+        assert!(!headers.contains_key(AUTHORIZATION));
+    }
+
+    fn too_many_requests(retry_after: &str) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, retry_after)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_429s() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(too_many_requests("0.01"));
+        upstream.respond(Response::new(Body::empty()));
+        let mut config = Config::from_env().unwrap();
+        config.handle_429 = Some((1, Duration::from_secs(5)));
+        let state = state(config, &upstream);
+
+        let response = handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let requests = upstream.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].path, Path::Gateway);
+    }
+
+    #[tokio::test]
+    async fn passes_on_429s_once_out_of_retries() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(too_many_requests("0"));
+        upstream.respond(too_many_requests("0"));
+        let mut config = Config::from_env().unwrap();
+        config.handle_429 = Some((1, Duration::from_secs(5)));
+        let state = state(config, &upstream);
+
+        let response = handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(upstream.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_429s_past_the_longest_wait() {
+        let upstream = Arc::new(Fake::default());
+        upstream.respond(too_many_requests("0.06"));
+        upstream.respond(too_many_requests("0.06"));
+        let mut config = Config::from_env().unwrap();
+        config.handle_429 = Some((3, Duration::from_millis(100)));
+        let state = state(config, &upstream);
+
+        let response = handle_request(&state, &connection(), get_gateway())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(upstream.requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retry_429_delays() {
+        let max = Duration::from_secs(2);
+
+        assert_eq!(
+            retry_429_delay(&too_many_requests("1.5"), max),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(retry_429_delay(&too_many_requests("2"), max), Some(max));
+        assert_eq!(retry_429_delay(&too_many_requests("2.001"), max), None);
+        assert_eq!(retry_429_delay(&too_many_requests("-1"), max), None);
+        assert_eq!(retry_429_delay(&too_many_requests("soon"), max), None);
+        assert_eq!(retry_429_delay(&Response::new(Body::empty()), max), None);
+    }
 }
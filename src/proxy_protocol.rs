@@ -0,0 +1,235 @@
+use std::{
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Start of every v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads the PROXY protocol header, in either its text (v1) or binary (v2)
+/// version, that a load balancer puts at the start of a connection to name the
+/// client it accepted it from. The stream is left at the first byte after it.
+///
+/// Returns `None` if the header doesn't name a client, such as for the load
+/// balancer's own health checks. Anything that isn't a valid header is an
+/// error, after which the connection should be closed.
+pub async fn read_header<S>(stream: &mut S) -> IoResult<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY" {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("connection doesn't start with a PROXY header"))
+    }
+}
+
+/// Reads the rest of a v1 header, such as `PROXY TCP4 1.2.3.4 5.6.7.8 1234 80\r\n`.
+async fn read_v1<S>(stream: &mut S) -> IoResult<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Read a byte at a time so that nothing past the header is consumed.
+    let mut line = b"PROXY".to_vec();
+
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid("PROXY header is too long"));
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    let line =
+        str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY header isn't text"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, port, _] | ["PROXY", "TCP6", source, _, port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("PROXY header has an invalid address"))?;
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| invalid("PROXY header has an invalid port"))?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("PROXY header is malformed")),
+    }
+}
+
+/// Reads the rest of a v2 header, after the first five bytes of the signature.
+async fn read_v2<S>(stream: &mut S) -> IoResult<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 11];
+    stream.read_exact(&mut header).await?;
+
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("PROXY header has an invalid signature"));
+    }
+
+    let version_command = header[7];
+    let family = header[8];
+    let length = u16::from_be_bytes([header[9], header[10]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("PROXY header has an unsupported version"));
+    }
+
+    // The addresses may be followed by extensions, which are skipped.
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the connection is the load balancer's own.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("PROXY header has an unknown command")),
+    }
+
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Unix sockets and unspecified families don't name an IP client.
+        0 | 3 => Ok(None),
+        _ => Err(invalid("PROXY header has invalid addresses")),
+    }
+}
+
+fn invalid(message: &'static str) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v2 header with the given command and family, followed by `addresses`.
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let mut stream = &b"PROXY TCP4 1.2.3.4 5.6.7.8 1234 80\r\nGET / HTTP/1.1"[..];
+        let peer = read_header(&mut stream).await.unwrap();
+
+        assert_eq!(peer, Some(SocketAddr::from(([1, 2, 3, 4], 1234))));
+        assert_eq!(stream, b"GET / HTTP/1.1");
+
+        let mut stream = &b"PROXY TCP6 ::1 ::2 4321 80\r\n"[..];
+        let peer = read_header(&mut stream).await.unwrap();
+
+        assert_eq!(
+            peer,
+            Some(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4321))
+        );
+
+        let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v1_headers() {
+        for header in &[
+            &b"PROXY TCP4 1.2.3 5.6.7.8 1234 80\r\n"[..],
+            b"PROXY TCP4 1.2.3.4 5.6.7.8 port 80\r\n",
+            b"PROXY TCP4 1.2.3.4\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            let mut stream = *header;
+            let why = read_header(&mut stream).await.unwrap_err();
+
+            assert_eq!(why.kind(), ErrorKind::InvalidData);
+        }
+
+        let long = [&b"PROXY "[..], &[b'1'; V1_MAX_LENGTH]].concat();
+        let why = read_header(&mut &long[..]).await.unwrap_err();
+        assert_eq!(why.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut ipv4 = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        ipv4.extend_from_slice(&1234_u16.to_be_bytes());
+        ipv4.extend_from_slice(&80_u16.to_be_bytes());
+        let mut header = v2(1, 0x11, &ipv4);
+        header.extend_from_slice(b"GET");
+        let mut stream = &header[..];
+
+        let peer = read_header(&mut stream).await.unwrap();
+        assert_eq!(peer, Some(SocketAddr::from(([1, 2, 3, 4], 1234))));
+        assert_eq!(stream, b"GET");
+
+        let mut ipv6 = Ipv6Addr::LOCALHOST.octets().to_vec();
+        ipv6.extend_from_slice(&[0; 16]);
+        ipv6.extend_from_slice(&4321_u16.to_be_bytes());
+        ipv6.extend_from_slice(&80_u16.to_be_bytes());
+        // Extensions after the addresses are skipped.
+        ipv6.extend_from_slice(&[0x04, 0, 1, 0]);
+        let header = v2(1, 0x21, &ipv6);
+
+        let peer = read_header(&mut &header[..]).await.unwrap();
+        assert_eq!(
+            peer,
+            Some(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4321))
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers_without_a_client() {
+        let local = v2(0, 0x11, &[0; 12]);
+        assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+
+        let unspecified = v2(1, 0x00, &[]);
+        assert_eq!(read_header(&mut &unspecified[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v2_headers() {
+        let mut bad_signature = v2(1, 0x11, &[0; 12]);
+        bad_signature[6] = b'X';
+        let unknown_command = v2(2, 0x11, &[0; 12]);
+        let short_addresses = v2(1, 0x11, &[0; 4]);
+        let mut bad_version = v2(1, 0x11, &[0; 12]);
+        bad_version[12] = 0x11;
+
+        for header in &[bad_signature, unknown_command, short_addresses, bad_version] {
+            let why = read_header(&mut &header[..]).await.unwrap_err();
+
+            assert_eq!(why.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
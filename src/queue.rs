@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+use twilight_http::routing::Path;
+
+/// Requests in flight for each ratelimit bucket, which twilight keys by path.
+///
+/// Twilight's ratelimiter queues requests for a bucket without bound, so this
+/// counts them on the way in instead and turns them away when there are too
+/// many.
+pub struct BucketQueues {
+    depths: Mutex<HashMap<Path, usize>>,
+    max_depth: usize,
+}
+
+impl BucketQueues {
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            depths: Mutex::new(HashMap::new()),
+            max_depth,
+        }
+    }
+
+    /// Counts a request against its bucket until the returned guard is dropped,
+    /// unless the bucket is already full.
+    pub fn enter(&self, path: &Path, route: &'static str) -> Option<Queued<'_>> {
+        let mut depths = self.depths.lock().unwrap_or_else(PoisonError::into_inner);
+        let depth = depths.entry(path.clone()).or_insert(0);
+
+        if *depth >= self.max_depth {
+            return None;
+        }
+
+        *depth += 1;
+
+        #[cfg(feature = "expose-metrics")]
+        crate::QUEUE_DEPTH.with_label_values(&[route]).inc();
+
+        Some(Queued {
+            path: path.clone(),
+            queues: self,
+            route,
+        })
+    }
+}
+
+/// A request counted against its bucket.
+pub struct Queued<'a> {
+    path: Path,
+    queues: &'a BucketQueues,
+    #[cfg_attr(not(feature = "expose-metrics"), allow(dead_code))]
+    route: &'static str,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut depths = self
+            .queues
+            .depths
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(depth) = depths.get_mut(&self.path) {
+            *depth -= 1;
+
+            if *depth == 0 {
+                depths.remove(&self.path);
+            }
+        }
+
+        #[cfg(feature = "expose-metrics")]
+        crate::QUEUE_DEPTH.with_label_values(&[self.route]).dec();
+    }
+}
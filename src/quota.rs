@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Requests per second each caller may make through the proxy, enforced with a
+/// token bucket per caller on top of Discord's own ratelimits.
+///
+/// A caller's bucket holds up to a second's worth of requests, so it can burst
+/// that many at once before being held to its rate.
+pub struct Quotas {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    default: Option<u32>,
+    limits: HashMap<String, u32>,
+}
+
+struct Bucket {
+    limit: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The requests left at `now`, having refilled since it was last used.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let refilled = self.tokens + now.duration_since(self.updated).as_secs_f64() * self.limit;
+
+        refilled.min(self.limit)
+    }
+
+    /// Takes a request from the bucket, returning how many are left or, if
+    /// there are none, how long until there's one.
+    fn take(&mut self, now: Instant) -> Result<u32, Duration> {
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / self.limit));
+        }
+
+        self.tokens -= 1.0;
+
+        Ok(self.tokens as u32)
+    }
+}
+
+impl Quotas {
+    pub fn new(limits: HashMap<String, u32>, default: Option<u32>) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            default,
+            limits,
+        }
+    }
+
+    /// Takes a request from the caller's quota, returning how many are left or,
+    /// if there are none, how long until there's one.
+    ///
+    /// Callers without a quota of their own or a default one aren't limited,
+    /// which is `Ok(None)`.
+    pub fn take(&self, caller: &str) -> Result<Option<u32>, Duration> {
+        let limit = match self.limits.get(caller).copied().or(self.default) {
+            Some(limit) => f64::from(limit),
+            None => return Ok(None),
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry(caller.to_owned()).or_insert(Bucket {
+            limit,
+            tokens: limit,
+            updated: now,
+        });
+
+        let remaining = bucket.take(now)?;
+
+        // A bucket that's refilled is the same as none at all, so those are
+        // dropped once there are many, rather than kept for every caller ever
+        // seen.
+        if buckets.len() > self.limits.len() + 1024 {
+            buckets.retain(|_, bucket| bucket.tokens_at(now) < bucket.limit);
+        }
+
+        Ok(Some(remaining))
+    }
+}
+
+/// Requests per second that may be sent to Discord through the whole proxy,
+/// whoever makes them, with a bucket like a caller's quota.
+pub struct GlobalLimit {
+    bucket: Mutex<Bucket>,
+}
+
+impl GlobalLimit {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                limit: f64::from(limit),
+                tokens: f64::from(limit),
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a request from the limit, or returns how long until there's one.
+    pub fn take(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+
+        bucket.take(Instant::now()).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_burst_then_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            limit: 2.0,
+            tokens: 2.0,
+            updated: start,
+        };
+
+        assert_eq!(bucket.take(start), Ok(1));
+        assert_eq!(bucket.take(start), Ok(0));
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(500)));
+
+        // Half a second at two a second refills one request.
+        assert_eq!(bucket.take(start + Duration::from_millis(500)), Ok(0));
+
+        // Refilling stops at the limit however long the bucket sat unused.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.tokens_at(later), 2.0);
+    }
+
+    #[test]
+    fn quotas_apply_per_caller() {
+        let limits = [("small".to_owned(), 1)].iter().cloned().collect();
+        let quotas = Quotas::new(limits, Some(2));
+
+        assert_eq!(quotas.take("small"), Ok(Some(0)));
+        assert!(quotas.take("small").is_err());
+
+        // Other callers get the default and a bucket of their own.
+        assert_eq!(quotas.take("other"), Ok(Some(1)));
+        assert_eq!(quotas.take("another"), Ok(Some(1)));
+    }
+
+    #[test]
+    fn callers_without_a_quota_are_not_limited() {
+        let quotas = Quotas::new(HashMap::new(), None);
+
+        for _ in 0..10 {
+            assert_eq!(quotas.take("anyone"), Ok(None));
+        }
+    }
+
+    #[test]
+    fn global_limit_is_shared() {
+        let limit = GlobalLimit::new(2);
+
+        assert_eq!(limit.take(), Ok(()));
+        assert_eq!(limit.take(), Ok(()));
+        assert!(limit.take().is_err());
+    }
+}
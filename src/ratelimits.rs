@@ -0,0 +1,113 @@
+use http::{HeaderMap, HeaderValue};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use twilight_http::routing::Path;
+
+/// Number of buckets remembered for a bot before the ones that have reset are
+/// forgotten.
+const MAX_BUCKETS: usize = 1024;
+
+/// The ratelimits Discord last reported for each bot's buckets, taken from the
+/// headers of its responses.
+///
+/// Twilight's ratelimiter doesn't expose what it knows, so this keeps its own
+/// copy for the admin endpoints. It's keyed the way twilight keys its buckets,
+/// by path.
+#[derive(Default)]
+pub struct Ratelimits {
+    bots: Mutex<HashMap<Option<u64>, HashMap<Path, Observed>>>,
+}
+
+struct Observed {
+    bucket: Option<String>,
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    reset: Option<Instant>,
+    route: &'static str,
+}
+
+impl Ratelimits {
+    /// Remembers the ratelimit headers of a response from Discord, if it has
+    /// any.
+    pub fn record(
+        &self,
+        bot_id: Option<u64>,
+        path: &Path,
+        route: &'static str,
+        headers: &HeaderMap,
+    ) {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+        };
+
+        if header("x-ratelimit-remaining").is_none() {
+            return;
+        }
+
+        let reset_after = header("x-ratelimit-reset-after")
+            .and_then(|value| value.parse::<f64>().ok())
+            // Anything beyond a day isn't a real reset, and could overflow.
+            .filter(|secs| (0.0..=86_400.0).contains(secs));
+        let observed = Observed {
+            bucket: header("x-ratelimit-bucket").map(str::to_owned),
+            limit: header("x-ratelimit-limit").and_then(|value| value.parse().ok()),
+            remaining: header("x-ratelimit-remaining").and_then(|value| value.parse().ok()),
+            reset: reset_after.map(|secs| Instant::now() + Duration::from_secs_f64(secs)),
+            route,
+        };
+
+        let mut bots = self.bots.lock().unwrap_or_else(PoisonError::into_inner);
+        let buckets = bots.entry(bot_id).or_default();
+
+        // Paths include ids, so a bot can touch any number of them. Buckets
+        // that have reset are no different from unknown ones, and are dropped
+        // once there are many.
+        if buckets.len() >= MAX_BUCKETS {
+            let now = Instant::now();
+            buckets.retain(|_, observed| observed.reset.is_some_and(|reset| reset > now));
+        }
+
+        buckets.insert(path.clone(), observed);
+    }
+
+    /// The buckets known for a bot, as JSON.
+    pub fn snapshot(&self, bot_id: Option<u64>) -> Value {
+        let bots = self.bots.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+
+        let buckets = bots.get(&bot_id).map_or_else(Vec::new, |buckets| {
+            buckets
+                .iter()
+                .map(|(path, observed)| {
+                    let reset_after_ms = observed
+                        .reset
+                        .map(|reset| reset.saturating_duration_since(now).as_millis() as u64);
+
+                    json!({
+                        "path": format!("{:?}", path),
+                        "route": observed.route,
+                        "bucket": observed.bucket,
+                        "limit": observed.limit,
+                        // The bucket has refilled once its reset has passed.
+                        "remaining": match reset_after_ms {
+                            Some(0) => observed.limit,
+                            _ => observed.remaining,
+                        },
+                        "reset_after_ms": reset_after_ms,
+                    })
+                })
+                .collect()
+        });
+
+        json!({
+            "bot_id": bot_id.map(|id| id.to_string()),
+            "buckets": buckets,
+        })
+    }
+}
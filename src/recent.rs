@@ -0,0 +1,53 @@
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Summaries of the most recently forwarded requests, for looking at what the
+/// proxy did without going through its logs.
+///
+/// Only the route is kept, not the path, so no ids, tokens or bodies are.
+pub struct RecentRequests {
+    capacity: usize,
+    entries: Mutex<VecDeque<Value>>,
+}
+
+impl RecentRequests {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Records a forwarded request, forgetting the oldest one if it's full.
+    pub fn record(&self, method: &str, route: &str, status: u16, size: usize) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let entry = json!({
+            "method": method,
+            "route": route,
+            "status": status,
+            "size": size,
+            "timestamp_ms": timestamp_ms,
+        });
+
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+
+        entries.push_back(entry);
+    }
+
+    /// The recorded requests, oldest first.
+    pub fn to_json(&self) -> Value {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        Value::Array(entries.iter().cloned().collect())
+    }
+}
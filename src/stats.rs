@@ -0,0 +1,52 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Counters describing what the proxy has been doing.
+#[derive(Debug)]
+pub struct Stats {
+    handled: AtomicU64,
+    in_flight: AtomicUsize,
+    started: Instant,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            handled: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Marks a request as in flight until the returned guard is dropped.
+    pub fn start(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        InFlight(self)
+    }
+
+    /// Number of requests that have been handled to completion.
+    pub fn handled(&self) -> u64 {
+        self.handled.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Guard for a request counted by [`Stats::start`].
+pub struct InFlight<'a>(&'a Stats);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.handled.fetch_add(1, Ordering::Relaxed);
+    }
+}
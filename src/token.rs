@@ -0,0 +1,280 @@
+use crate::{
+    config,
+    error::{ReadingEnv, ReadingFile, RequestError, TokenError},
+};
+use snafu::ResultExt;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+/// Where the global Discord token is read from, configured with `TOKEN_SOURCE`.
+///
+/// New backends, such as a secrets manager, are added as variants here.
+#[derive(Clone, Debug)]
+pub enum TokenSource {
+    /// An environment variable, given as `env://NAME`, which the config file
+    /// read at startup may set too.
+    Env(String),
+    /// A file holding only the token, given as `file:///path`.
+    ///
+    /// The file is read again each time the clients are rebuilt, so they pick
+    /// up a rotated token.
+    File(PathBuf),
+}
+
+impl TokenSource {
+    pub fn from_env() -> Result<Self, TokenError> {
+        match config::lookup(config::startup_file(), "TOKEN_SOURCE") {
+            Ok(uri) => Self::parse(&uri),
+            Err(_) => Ok(Self::Env("DISCORD_TOKEN".into())),
+        }
+    }
+
+    fn parse(uri: &str) -> Result<Self, TokenError> {
+        if let Some(name) = uri.strip_prefix("env://") {
+            Ok(Self::Env(name.into()))
+        } else if let Some(path) = uri.strip_prefix("file://") {
+            Ok(Self::File(path.into()))
+        } else {
+            Err(TokenError::UnknownSource { uri: uri.into() })
+        }
+    }
+
+    /// Reads the tokens, blocking on the file if there is one as it's only
+    /// read when building clients, which blocks anyway.
+    pub fn load(&self) -> Result<String, TokenError> {
+        let tokens = match self {
+            Self::Env(name) => config::lookup(config::startup_file(), name)
+                .context(ReadingEnv { name: name.clone() })?,
+            Self::File(path) => {
+                let contents =
+                    fs::read_to_string(path).context(ReadingFile { path: path.clone() })?;
+
+                contents.trim().to_owned()
+            }
+        };
+
+        if split(&tokens).next().is_none() {
+            return Err(TokenError::Empty);
+        }
+
+        Ok(tokens)
+    }
+}
+
+/// The tokens in a comma-separated list, without the empty entries left by
+/// stray commas or whitespace.
+pub fn split(tokens: &str) -> impl Iterator<Item = &str> {
+    tokens
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Hashes a token so it can be told apart from others without being kept.
+pub fn hash(token: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    token.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// The bot's user id, which a token's first segment is the base64 of.
+///
+/// The token may be given as a whole `Authorization` value, with its `Bot `
+/// prefix. This only looks at the token itself, so it can be called on any
+/// untrusted input.
+pub fn derive_bot_id(token: &str) -> Result<u64, RequestError> {
+    let token = token.strip_prefix("Bot ").unwrap_or(token);
+    let segment = token.split('.').next().unwrap_or_default();
+    let segment = segment.trim_end_matches('=');
+    let decoded = base64::decode_config(segment, base64::STANDARD_NO_PAD)
+        .or_else(|_| base64::decode_config(segment, base64::URL_SAFE_NO_PAD))
+        .map_err(|_| RequestError::InvalidBotId)?;
+
+    std::str::from_utf8(&decoded)
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .ok_or(RequestError::InvalidBotId)
+}
+
+/// The most recently used tokens' bot ids, keyed by the tokens' hashes, so that
+/// they aren't decoded for every request.
+pub struct BotIds {
+    inner: Mutex<BotIdsInner>,
+    size: usize,
+}
+
+#[derive(Default)]
+struct BotIdsInner {
+    /// Each token's bot id and when it was last used.
+    entries: HashMap<u64, (Option<u64>, u64)>,
+    /// Token hashes by when they were last used, oldest first.
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl BotIds {
+    pub fn new(size: usize) -> Self {
+        Self {
+            inner: Mutex::new(BotIdsInner::default()),
+            size,
+        }
+    }
+
+    pub fn get(&self, authorization: &str) -> Option<u64> {
+        let hash = hash(authorization.as_bytes());
+        let mut guard = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *guard;
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((bot_id, used)) = inner.entries.get_mut(&hash) {
+            let (bot_id, previous) = (*bot_id, *used);
+            *used = tick;
+            inner.recency.remove(&previous);
+            inner.recency.insert(tick, hash);
+
+            return bot_id;
+        }
+
+        let bot_id = derive_bot_id(authorization).ok();
+
+        if inner.entries.len() >= self.size {
+            let oldest = inner.recency.keys().next().copied();
+
+            if let Some(oldest) = oldest.and_then(|used| inner.recency.remove(&used)) {
+                inner.entries.remove(&oldest);
+            }
+        }
+
+        inner.entries.insert(hash, (bot_id, tick));
+        inner.recency.insert(tick, hash);
+
+        bot_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn derives_bot_ids() {
+        let id = 80_351_110_224_678_912;
+
+        assert_eq!(derive_bot_id("ODAzNTExMTAyMjQ2Nzg5MTI.X.Y").ok(), Some(id));
+        assert_eq!(
+            derive_bot_id("Bot ODAzNTExMTAyMjQ2Nzg5MTI.X.Y").ok(),
+            Some(id)
+        );
+        // Padding is accepted whether or not it's there.
+        assert_eq!(derive_bot_id("ODAzNTExMTAyMjQ2Nzg5MTI=.X.Y").ok(), Some(id));
+        assert_eq!(derive_bot_id("MTIz").ok(), Some(123));
+    }
+
+    #[test]
+    fn rejects_tokens_without_a_bot_id() {
+        for token in &[
+            "",
+            "Bot ",
+            ".X.Y",
+            "not base64!.X",
+            "MT4-.X",
+            "Bearer MTIz.X",
+        ] {
+            assert!(
+                matches!(derive_bot_id(token), Err(RequestError::InvalidBotId)),
+                "{:?} has a bot id",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn bot_ids_evict_the_least_recently_used() {
+        let bot_ids = BotIds::new(2);
+        let (first, second, third) = ("Bot MTIz.X", "Bot MTI0.X", "Bot MTI1.X");
+
+        assert_eq!(bot_ids.get(first), Some(123));
+        assert_eq!(bot_ids.get(second), Some(124));
+        // Using the first again makes the second the oldest.
+        assert_eq!(bot_ids.get(first), Some(123));
+        assert_eq!(bot_ids.get(third), Some(125));
+
+        let inner = bot_ids.inner.lock().unwrap();
+        assert_eq!(inner.entries.len(), 2);
+        assert_eq!(inner.recency.len(), 2);
+        assert!(inner.entries.contains_key(&hash(first.as_bytes())));
+        assert!(!inner.entries.contains_key(&hash(second.as_bytes())));
+        assert!(inner.entries.contains_key(&hash(third.as_bytes())));
+    }
+
+    #[test]
+    fn bot_ids_cache_invalid_tokens() {
+        let bot_ids = BotIds::new(2);
+
+        assert_eq!(bot_ids.get("Bot .X"), None);
+        assert_eq!(bot_ids.get("Bot .X"), None);
+        assert_eq!(bot_ids.inner.lock().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn splits_tokens() {
+        let tokens = split(" a, b ,,c, ").collect::<Vec<_>>();
+
+        assert_eq!(tokens, ["a", "b", "c"]);
+        assert_eq!(split(" , ,").next(), None);
+    }
+
+    #[test]
+    fn loading_no_tokens_fails() {
+        env::set_var("TOKEN_TEST_ONLY_COMMAS", " , ");
+        let source = TokenSource::parse("env://TOKEN_TEST_ONLY_COMMAS").unwrap();
+
+        assert!(matches!(source.load(), Err(TokenError::Empty)));
+
+        let path = env::temp_dir().join(format!("token-test-{}", std::process::id()));
+        fs::write(&path, "\n").unwrap();
+        let source = TokenSource::File(path.clone());
+        let loaded = source.load();
+        fs::remove_file(path).unwrap();
+
+        assert!(matches!(loaded, Err(TokenError::Empty)));
+    }
+    #[test]
+    fn never_panics_deriving_bot_ids() {
+        fn derive(token: String) -> bool {
+            let _ = derive_bot_id(&token);
+
+            true
+        }
+
+        quickcheck::quickcheck(derive as fn(String) -> bool);
+    }
+
+    #[test]
+    fn derives_the_bot_id_any_token_was_made_for() {
+        fn round_trips(id: u64, rest: String, url_safe: bool) -> bool {
+            let config = if url_safe {
+                base64::URL_SAFE_NO_PAD
+            } else {
+                base64::STANDARD_NO_PAD
+            };
+            let token = format!(
+                "Bot {}.{}",
+                base64::encode_config(id.to_string(), config),
+                rest
+            );
+
+            derive_bot_id(&token).ok() == Some(id)
+        }
+
+        quickcheck::quickcheck(round_trips as fn(u64, String, bool) -> bool);
+    }
+}
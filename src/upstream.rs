@@ -0,0 +1,501 @@
+use http::header::AUTHORIZATION;
+use hyper::{Body, Response};
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock, TryLockError,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+use twilight_http::{
+    client::Client,
+    error::Error,
+    request::{Method, Request},
+    routing::Path,
+};
+
+pub type UpstreamFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send + 'a>>;
+
+/// Something that sends raw requests to Discord.
+///
+/// The request handler only talks to Discord through this trait, so it can be
+/// driven by something other than twilight's [`Client`], such as a fake that
+/// records requests and returns canned responses.
+pub trait Upstream: Send + Sync {
+    fn raw(&self, request: Request) -> UpstreamFuture<'_>;
+
+    /// Sends a cheap request through every underlying client, keeping a
+    /// connection to Discord open in each of their pools.
+    fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+
+    /// Replaces the underlying clients with newly built ones, returning whether
+    /// that's supported.
+    fn rebuild(&self) -> bool {
+        false
+    }
+
+    /// Whether a request could be sent right now without waiting on a lock,
+    /// which a stuck client rebuild would be holding.
+    fn is_unlocked(&self) -> bool {
+        true
+    }
+}
+
+impl Upstream for Client {
+    fn raw(&self, request: Request) -> UpstreamFuture<'_> {
+        Box::pin(Client::raw(self, request))
+    }
+
+    fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let request = Request {
+                body: None,
+                form: None,
+                headers: None,
+                method: Method::Get,
+                path: Path::Gateway,
+                path_str: "gateway".into(),
+            };
+
+            if let Err(why) = Client::raw(self, request).await {
+                debug!("Keepalive ping failed: {}", why);
+            }
+        })
+    }
+}
+
+/// Spreads requests round-robin across several clients, each with its own token
+/// and ratelimiter.
+pub struct RoundRobin {
+    clients: Vec<Client>,
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    /// Creates a round-robin over clients, of which there must be at least one.
+    pub fn new(clients: Vec<Client>) -> Self {
+        assert!(!clients.is_empty(), "round-robin needs at least one client");
+
+        Self {
+            clients,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Upstream for RoundRobin {
+    fn raw(&self, request: Request) -> UpstreamFuture<'_> {
+        // A request with its own token is always sent by the same client, so
+        // that token's limits are tracked by a single ratelimiter.
+        let has_token = request
+            .headers
+            .as_ref()
+            .is_some_and(|headers| headers.contains_key(AUTHORIZATION));
+
+        let index = if has_token {
+            0
+        } else {
+            self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len()
+        };
+
+        Upstream::raw(&self.clients[index], request)
+    }
+
+    fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            for client in &self.clients {
+                Upstream::ping(client).await;
+            }
+        })
+    }
+}
+
+/// Sends requests to a fallback upstream when the primary one can't be reached.
+///
+/// Only requests the primary fails to send at all are failed over, such as on
+/// a connection error or a timeout. Responses from Discord, whatever their
+/// status, are passed on. Unless nothing was sent, which is only known of
+/// connection errors, only requests that are safe to send twice are failed
+/// over, since Discord may have acted on the first.
+pub struct Failover {
+    fallback: Box<dyn Upstream>,
+    primary: Box<dyn Upstream>,
+}
+
+impl Failover {
+    pub fn new(primary: Box<dyn Upstream>, fallback: Box<dyn Upstream>) -> Self {
+        Self { fallback, primary }
+    }
+}
+
+impl Upstream for Failover {
+    fn raw(&self, request: Request) -> UpstreamFuture<'_> {
+        // Forms can't be copied for a second attempt, but the proxy never
+        // sends any.
+        let copy = match request.form {
+            Some(_) => None,
+            None => Some(Request {
+                body: request.body.clone(),
+                form: None,
+                headers: request.headers.clone(),
+                method: request.method,
+                path: request.path.clone(),
+                path_str: request.path_str.clone(),
+            }),
+        };
+
+        Box::pin(async move {
+            match (self.primary.raw(request).await, copy) {
+                (Err(why), Some(copy)) if can_fail_over(copy.method, &why) => {
+                    warn!("Failing over to the fallback upstream: {}", why);
+                    #[cfg(feature = "expose-metrics")]
+                    crate::FAILOVERS.inc();
+
+                    self.fallback.raw(copy).await
+                }
+                (result, _) => result,
+            }
+        })
+    }
+
+    fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            self.primary.ping().await;
+            self.fallback.ping().await;
+        })
+    }
+}
+
+/// Whether a request that failed with an error may be sent again through the
+/// fallback.
+fn can_fail_over(method: Method, why: &Error) -> bool {
+    match why {
+        // The token is invalid, for the fallback as much as for the primary.
+        Error::Unauthorized => false,
+        Error::RequestError { source } if source.is_connect() => true,
+        _ => matches!(method, Method::Delete | Method::Get | Method::Put),
+    }
+}
+
+/// Builds a new upstream.
+pub type Build = Box<dyn Fn() -> Box<dyn Upstream> + Send + Sync>;
+
+/// Replaces the upstream it wraps with a newly built one on demand or once it
+/// reaches a maximum age, so that no client and its connection pool lives
+/// forever.
+///
+/// Requests already sent through an old upstream finish on it.
+pub struct Rebuilding {
+    build: Build,
+    /// Held while the clients are being rebuilt for their age.
+    building: Mutex<()>,
+    current: RwLock<Arc<Generation>>,
+    max_age: Option<Duration>,
+}
+
+impl Rebuilding {
+    pub fn new(max_age: Option<Duration>, build: Build) -> Self {
+        let current = Generation::new(build());
+
+        Self {
+            build,
+            building: Mutex::new(()),
+            current: RwLock::new(current),
+            max_age,
+        }
+    }
+
+    /// The upstream to send a request through, rebuilding it first if it's too
+    /// old.
+    ///
+    /// The new upstream is built without holding the lock, so other requests
+    /// carry on through the old one meanwhile rather than waiting.
+    fn current(&self) -> Arc<Generation> {
+        let old = self
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+
+        if !self.is_expired(old.built) {
+            return old;
+        }
+
+        let _building = match self.building.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            // Another request is already rebuilding it.
+            Err(TryLockError::WouldBlock) => return old,
+        };
+
+        info!(
+            "Rebuilding clients after {}s",
+            old.built.elapsed().as_secs()
+        );
+        let upstream = Generation::new((self.build)());
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+
+        // It may have been rebuilt on demand while this was building.
+        if !self.is_expired(current.built) {
+            return current.clone();
+        }
+
+        let old = mem::replace(&mut *current, upstream.clone());
+        drop(current);
+        old.retire();
+
+        upstream
+    }
+
+    fn is_expired(&self, built: Instant) -> bool {
+        self.max_age
+            .is_some_and(|max_age| built.elapsed() >= max_age)
+    }
+}
+
+impl Upstream for Rebuilding {
+    fn raw(&self, request: Request) -> UpstreamFuture<'_> {
+        let upstream = self.current();
+
+        Box::pin(async move { upstream.upstream.raw(request).await })
+    }
+
+    fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let upstream = self.current();
+
+        Box::pin(async move { upstream.upstream.ping().await })
+    }
+
+    fn is_unlocked(&self) -> bool {
+        !matches!(self.current.try_read(), Err(TryLockError::WouldBlock))
+    }
+
+    fn rebuild(&self) -> bool {
+        let upstream = Generation::new((self.build)());
+        let old = mem::replace(
+            &mut *self.current.write().unwrap_or_else(PoisonError::into_inner),
+            upstream,
+        );
+        old.retire();
+
+        true
+    }
+}
+
+/// An upstream built by [`Rebuilding`], which logs when it's released after
+/// being replaced so a lingering reference to old clients shows up.
+struct Generation {
+    built: Instant,
+    retired: AtomicBool,
+    upstream: Box<dyn Upstream>,
+}
+
+impl Generation {
+    fn new(upstream: Box<dyn Upstream>) -> Arc<Self> {
+        Arc::new(Self {
+            built: Instant::now(),
+            retired: AtomicBool::new(false),
+            upstream,
+        })
+    }
+
+    /// Drops the rebuilding upstream's reference to this one, which releases it
+    /// and its connection pool now unless requests are still in flight on it.
+    fn retire(self: Arc<Self>) {
+        self.retired.store(true, Ordering::Relaxed);
+        #[cfg(feature = "expose-metrics")]
+        crate::RETIRED_CLIENTS.inc();
+
+        let in_flight = Arc::strong_count(&self) - 1;
+
+        if in_flight > 0 {
+            debug!(
+                "Old clients are released once {} requests in flight finish",
+                in_flight
+            );
+        }
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        if self.retired.load(Ordering::Relaxed) {
+            info!(
+                "Old clients released after {}s",
+                self.built.elapsed().as_secs()
+            );
+            #[cfg(feature = "expose-metrics")]
+            crate::RETIRED_CLIENTS.dec();
+        }
+    }
+}
+
+/// An upstream for tests, which records the requests sent through it and
+/// answers them with the responses or errors queued for it in turn, each after
+/// its delay, or with an empty `200 OK` once there are none left.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Fake {
+    pub requests: Mutex<Vec<Request>>,
+    responses: Mutex<std::collections::VecDeque<Answer>>,
+}
+
+/// A response or error [`Fake`] answers with, and how long it waits first.
+#[cfg(test)]
+type Answer = (Duration, Result<Response<Body>, Error>);
+
+#[cfg(test)]
+impl Fake {
+    pub fn respond(&self, response: Response<Body>) {
+        self.respond_after(Duration::from_secs(0), response);
+    }
+
+    pub fn respond_after(&self, delay: Duration, response: Response<Body>) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back((delay, Ok(response)));
+    }
+
+    pub fn fail(&self, why: Error) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back((Duration::from_secs(0), Err(why)));
+    }
+}
+
+#[cfg(test)]
+impl Upstream for Arc<Fake> {
+    fn raw(&self, request: Request) -> UpstreamFuture<'_> {
+        self.requests.lock().unwrap().push(request);
+        let response = self.responses.lock().unwrap().pop_front();
+
+        Box::pin(async move {
+            match response {
+                Some((delay, response)) => {
+                    tokio::time::sleep(delay).await;
+
+                    response
+                }
+                None => Ok(Response::default()),
+            }
+        })
+    }
+
+    fn ping(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, thread};
+
+    fn gateway() -> Request {
+        Request {
+            body: None,
+            form: None,
+            headers: None,
+            method: Method::Get,
+            path: Path::Gateway,
+            path_str: "gateway".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_carry_on_through_old_clients_while_rebuilding() {
+        let built = Arc::new(Mutex::new(Vec::<Arc<Fake>>::new()));
+        let (started, building) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let built_by = built.clone();
+
+        let rebuilding = Arc::new(Rebuilding::new(
+            Some(Duration::from_millis(100)),
+            Box::new(move || -> Box<dyn Upstream> {
+                let fake = Arc::new(Fake::default());
+                let count = {
+                    let mut built = built_by.lock().unwrap();
+                    built.push(fake.clone());
+
+                    built.len()
+                };
+
+                // The first rebuild is held up until the test lets it finish.
+                if count == 2 {
+                    started.send(()).unwrap();
+                    released.lock().unwrap().recv().unwrap();
+                }
+
+                Box::new(fake)
+            }),
+        ));
+
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        let rebuilder = rebuilding.clone();
+        let rebuild = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+
+            runtime.block_on(rebuilder.raw(gateway())).unwrap();
+        });
+        building.recv().unwrap();
+
+        // Neither waits on the rebuild.
+        rebuilding.raw(gateway()).await.unwrap();
+        assert!(rebuilding.is_unlocked());
+
+        release.send(()).unwrap();
+        rebuild.join().unwrap();
+        rebuilding.raw(gateway()).await.unwrap();
+
+        let built = built.lock().unwrap();
+        assert_eq!(built[0].requests.lock().unwrap().len(), 1);
+        assert_eq!(built[1].requests.lock().unwrap().len(), 2);
+    }
+    async fn timed_out() -> Error {
+        let pending = std::future::pending::<()>();
+        let source = tokio::time::timeout(Duration::from_millis(0), pending)
+            .await
+            .unwrap_err();
+
+        Error::RequestTimedOut { source }
+    }
+
+    #[tokio::test]
+    async fn fails_over_only_requests_safe_to_send_twice() {
+        let primary = Arc::new(Fake::default());
+        let fallback = Arc::new(Fake::default());
+        let failover = Failover::new(Box::new(primary.clone()), Box::new(fallback.clone()));
+        let create_message = || Request {
+            method: Method::Post,
+            path: Path::ChannelsIdMessages(1),
+            path_str: "channels/1/messages".into(),
+            ..gateway()
+        };
+
+        primary.fail(timed_out().await);
+        assert!(failover.raw(gateway()).await.is_ok());
+
+        // Discord may have created the message before the primary timed out.
+        primary.fail(timed_out().await);
+        assert!(failover.raw(create_message()).await.is_err());
+
+        primary.fail(Error::Unauthorized);
+        assert!(matches!(
+            failover.raw(gateway()).await,
+            Err(Error::Unauthorized)
+        ));
+
+        assert_eq!(primary.requests.lock().unwrap().len(), 3);
+        assert_eq!(fallback.requests.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,82 @@
+use serde_json::{json, Value};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of tokens remembered before the least recently used is forgotten.
+const MAX_TOKENS: usize = 10_000;
+
+/// When each token was first and last used through the proxy, and how often,
+/// for auditing which bots are active and how long their tokens have lived.
+///
+/// Tokens are only known by their hashes, and the proxy's own by the hash `0`.
+#[derive(Default)]
+pub struct TokenUsage {
+    tokens: Mutex<HashMap<u64, Usage>>,
+}
+
+struct Usage {
+    bot_id: Option<u64>,
+    first_seen_ms: u64,
+    last_used_ms: u64,
+    requests: u64,
+}
+
+impl TokenUsage {
+    /// Counts a request made with the token with this hash, looking up its bot
+    /// only the first time the token is seen.
+    pub fn record(&self, token_hash: u64, bot_id: impl FnOnce() -> Option<u64>) {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let mut tokens = self.tokens.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if tokens.len() >= MAX_TOKENS && !tokens.contains_key(&token_hash) {
+            let oldest = tokens
+                .iter()
+                .min_by_key(|(_, usage)| usage.last_used_ms)
+                .map(|(hash, _)| *hash);
+
+            if let Some(oldest) = oldest {
+                tokens.remove(&oldest);
+            }
+        }
+
+        let usage = tokens.entry(token_hash).or_insert_with(|| Usage {
+            bot_id: bot_id(),
+            first_seen_ms: now_ms,
+            last_used_ms: now_ms,
+            requests: 0,
+        });
+
+        usage.last_used_ms = now_ms;
+        usage.requests += 1;
+    }
+
+    /// The tokens seen, most recently used first.
+    pub fn to_json(&self) -> Value {
+        let tokens = self.tokens.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut entries = tokens.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, usage)| Reverse(usage.last_used_ms));
+
+        let entries = entries
+            .into_iter()
+            .map(|(hash, usage)| {
+                json!({
+                    "token_hash": Some(hash)
+                        .filter(|hash| **hash != 0)
+                        .map(|hash| format!("{:016x}", hash)),
+                    "bot_id": usage.bot_id.map(|id| id.to_string()),
+                    "first_seen_ms": usage.first_seen_ms,
+                    "last_used_ms": usage.last_used_ms,
+                    "requests": usage.requests,
+                })
+            })
+            .collect();
+
+        Value::Array(entries)
+    }
+}